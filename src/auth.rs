@@ -33,7 +33,7 @@ pub async fn auth_layer(
                 let credentials = String::from_utf8(STANDARD.decode(b64).unwrap_or(Vec::new())).unwrap_or("".to_owned());
                 let parts = credentials.split_once(':').unwrap_or(("", ""));
 
-                if let Some(_) = get_user_by_credentials(state.clone(), &parts.0.to_owned(), &parts.1.to_owned()).await {
+                if get_user_by_credentials(state.clone(), &parts.0.to_owned(), &parts.1.to_owned()).await.is_some() {
                     Ok(next.run(request).await)
                 } else {
                     log::info!(
@@ -101,7 +101,7 @@ async fn get_user_by_credentials(state: SafeAppState, username: &String, passwor
         if user.username == *username {
             if lock
                 .config
-                .verify_password(&password, &user.password)
+                .verify_password(password, &user.password)
             {
                 return Some(id);
             }
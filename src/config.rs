@@ -2,9 +2,11 @@ use argon2::{
     password_hash::{PasswordHashString, Salt},
     Argon2, PasswordHasher, PasswordVerifier,
 };
+use chrono::{DateTime, Timelike};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
 
-use crate::storage::get_storage_path;
+use crate::{storage::get_storage_path, timers::{day_of_week, MINUTES_IN_A_DAY}};
 
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub struct Config {
@@ -12,6 +14,126 @@ pub struct Config {
     pub user_token_expiry_time_seconds: u64,
     // to overcome musl (i guess?) bug where local timezone is ignored
     pub timezone_override: Option<String>,
+    // broker used by mqtt switches
+    pub mqtt: Option<MqttConfig>,
+    // how often switches without their own `poll_interval_seconds` are polled, 5 seconds when missing
+    pub poll_interval_seconds: Option<u64>,
+    // electricity prices used to compute what metered switches cost
+    pub tariff: Option<TariffConfig>,
+    // where the switches are, needed by timers that follow sunrise/sunset
+    pub location: Option<LocationConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocationConfig {
+    pub latitude: f64,  // degrees, north is positive
+    pub longitude: f64, // degrees, east is positive
+}
+
+/*
+* `price_per_kwh` applies whenever none of the time-of-use `periods` does, the first matching period wins.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TariffConfig {
+    pub price_per_kwh: f64,
+    #[serde(default)]
+    pub currency: String,
+    #[serde(default)]
+    pub periods: Vec<TariffPeriod>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TariffPeriod {
+    pub price_per_kwh: f64,
+    pub start_time: u32, // Start time in minutes after midnight
+    pub end_time: u32,   // End time in minutes after midnight (excluded), lower than start_time when spanning midnight
+    #[serde(default)]
+    pub days: Vec<u8>,   // Days the period starts on (0=Monday, 6=Sunday, see `day_of_week`), every day when empty
+}
+
+impl TariffPeriod {
+    fn applies_at(&self, weekday: u8, minutes_since_midnight: u32) -> bool {
+        let matches_day = |day: u8| self.days.is_empty() || self.days.contains(&day);
+
+        if self.start_time <= self.end_time {
+            matches_day(weekday) && minutes_since_midnight >= self.start_time && minutes_since_midnight < self.end_time
+        } else {
+            // after midnight the period belongs to the day before
+            (matches_day(weekday) && minutes_since_midnight >= self.start_time)
+                || (matches_day((weekday + 6) % 7) && minutes_since_midnight < self.end_time)
+        }
+    }
+}
+
+impl TariffConfig {
+    // Reasons why the tariff can not be used, empty when it is fine.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        for (i, period) in self.periods.iter().enumerate() {
+            if period.start_time >= MINUTES_IN_A_DAY {
+                errors.push(format!("periods[{}].start_time must be between 0 and {}", i, MINUTES_IN_A_DAY - 1));
+            }
+            if period.end_time >= MINUTES_IN_A_DAY {
+                errors.push(format!("periods[{}].end_time must be between 0 and {}", i, MINUTES_IN_A_DAY - 1));
+            }
+            if period.days.iter().any(|x| *x > 6) {
+                errors.push(format!("periods[{}].days must be between 0 (Monday) and 6 (Sunday)", i));
+            }
+        }
+
+        errors
+    }
+
+    pub fn price_at(&self, time: DateTime<Tz>) -> f64 {
+        let weekday = day_of_week(&time);
+        let minutes_since_midnight = time.hour() * 60 + time.minute();
+
+        self.periods
+            .iter()
+            .find(|x| x.applies_at(weekday, minutes_since_midnight))
+            .map(|x| x.price_per_kwh)
+            .unwrap_or(self.price_per_kwh)
+    }
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "remote_switch_manager".to_owned()
+}
+
+/*
+* Timezone used for anything calendar related (timers, daily/monthly aggregates), the system one
+* unless `timezone_override` is set.
+*/
+pub fn get_timezone(timezone_override: &Option<String>) -> Tz {
+    match timezone_override {
+        Some(tz_name) => tz_name
+            .parse()
+            .expect("Invalid timezone name. Use a valid IANA timezone, e.g., 'Europe/Rome'."),
+        None => {
+            let tz_name = iana_time_zone::get_timezone()
+                .expect("Could not determine timezone.");
+
+            tz_name
+                .parse()
+                .expect("Invalid timezone name.")
+        }
+    }
 }
 
 impl Config {
@@ -35,14 +157,19 @@ impl Config {
             std::fs::write(&config_toml, toml_s)
                 .expect("Could not write to config.toml, check permissions");
         }
-        return toml::from_str(
+        let config: Self = toml::from_str(
             &std::fs::read_to_string(&config_toml)
                 .expect("Could not read config.toml, make sure permissions are alright"),
         )
         .expect("Could not parse config.toml. Double check syntax and/or delete it.");
+
+        if let Some(errors) = config.tariff.as_ref().map(TariffConfig::validate).filter(|x| !x.is_empty()) {
+            panic!("Invalid [tariff] in config.toml: {}", errors.join("; "));
+        }
+        config
     }
 
-    pub fn get_salt(&self) -> Result<Salt, argon2::password_hash::Error> {
+    pub fn get_salt(&self) -> Result<Salt<'_>, argon2::password_hash::Error> {
         Salt::from_b64(&self.user_pass_hash)
     }
 
@@ -58,16 +185,89 @@ impl Config {
         hashed_pass.serialize().to_string()
     }
 
-    pub fn verify_password(&self, plain_password: &String, hash: &String) -> bool {
+    pub fn verify_password(&self, plain_password: &String, hash: &str) -> bool {
         let a2 = Argon2::default();
-        match a2.verify_password(
+        a2.verify_password(
             plain_password.as_bytes(),
             &PasswordHashString::parse(hash, argon2::password_hash::Encoding::B64)
                 .expect("Could not parse password hash")
                 .password_hash(),
-        ) {
-            Ok(_) => true,
-            Err(_) => false,
+        )
+        .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    // 0.30 during the day, 0.10 on weeknights from 23:00 to 07:00
+    fn night_tariff() -> TariffConfig {
+        TariffConfig {
+            price_per_kwh: 0.30,
+            currency: "EUR".to_owned(),
+            periods: vec![TariffPeriod { price_per_kwh: 0.10, start_time: 23 * 60, end_time: 7 * 60, days: vec![0, 1, 2, 3, 4] }],
         }
     }
+
+    #[test]
+    fn periods_within_a_day_end_before_end_time() {
+        let period = TariffPeriod { price_per_kwh: 0.2, start_time: 8 * 60, end_time: 12 * 60, days: vec![2] };
+
+        assert!(!period.applies_at(2, 8 * 60 - 1));
+        assert!(period.applies_at(2, 8 * 60));
+        assert!(period.applies_at(2, 12 * 60 - 1));
+        assert!(!period.applies_at(2, 12 * 60));
+        assert!(!period.applies_at(3, 9 * 60));
+    }
+
+    #[test]
+    fn overnight_periods_belong_to_the_day_they_start_on() {
+        let period = &night_tariff().periods[0];
+
+        // friday night, until saturday morning
+        assert!(period.applies_at(4, 23 * 60));
+        assert!(period.applies_at(5, 6 * 60 + 59));
+        assert!(!period.applies_at(5, 7 * 60));
+        // saturday and sunday nights are not part of it, monday morning is the end of sunday night
+        assert!(!period.applies_at(5, 23 * 60));
+        assert!(!period.applies_at(0, 3 * 60));
+        // monday night runs into tuesday
+        assert!(period.applies_at(1, 3 * 60));
+    }
+
+    #[test]
+    fn periods_without_days_apply_every_day() {
+        let period = TariffPeriod { price_per_kwh: 0.2, start_time: 22 * 60, end_time: 6 * 60, days: vec![] };
+
+        assert!((0..7).all(|day| period.applies_at(day, 23 * 60) && period.applies_at(day, 60)));
+    }
+
+    #[test]
+    fn price_follows_the_local_time() {
+        let tariff = night_tariff();
+        let amsterdam = chrono_tz::Europe::Amsterdam;
+
+        // tuesday 2026-10-20 01:00 in Amsterdam is still monday 23:00 in UTC
+        assert_eq!(tariff.price_at(amsterdam.with_ymd_and_hms(2026, 10, 20, 1, 0, 0).unwrap()), 0.10);
+        assert_eq!(tariff.price_at(amsterdam.with_ymd_and_hms(2026, 10, 20, 12, 0, 0).unwrap()), 0.30);
+        // monday 2026-10-19 01:00 follows a sunday night
+        assert_eq!(tariff.price_at(amsterdam.with_ymd_and_hms(2026, 10, 19, 1, 0, 0).unwrap()), 0.30);
+    }
+
+    #[test]
+    fn periods_out_of_range_are_rejected() {
+        let mut tariff = night_tariff();
+        assert!(tariff.validate().is_empty());
+
+        tariff.periods.push(TariffPeriod { price_per_kwh: 0.2, start_time: 1440, end_time: 2000, days: vec![7] });
+
+        assert_eq!(tariff.validate(), vec![
+            "periods[1].start_time must be between 0 and 1439",
+            "periods[1].end_time must be between 0 and 1439",
+            "periods[1].days must be between 0 (Monday) and 6 (Sunday)",
+        ]);
+    }
 }
@@ -0,0 +1,136 @@
+use std::process::Output;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::time::{timeout, Duration};
+
+use super::DeviceData;
+use super::DeviceError;
+use super::DeviceResult;
+use super::DeviceStatus;
+use super::STATUS_TIMEOUT;
+use super::Switch;
+
+/*
+* Local executables driving a switch, each one given as program + arguments (e.g. `["systemctl", "start", "minecraft"]`).
+* `status_command` decides the status through its stdout when that is `on`/`off`, through its exit code (0 = on) otherwise.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CommandConfig {
+    pub on_command: Vec<String>,
+    pub off_command: Vec<String>,
+    pub status_command: Option<Vec<String>>,
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+fn default_timeout_seconds() -> u64 {
+    10
+}
+
+pub async fn run_command(command: &[String], timeout_seconds: u64) -> DeviceResult<Output> {
+    let Some((program, args)) = command.split_first() else {
+        return Err(DeviceError::Failed("empty command".to_owned()));
+    };
+
+    let child = tokio::process::Command::new(program)
+        .args(args)
+        .kill_on_drop(true)
+        .output();
+
+    match timeout(Duration::from_secs(timeout_seconds), child).await {
+        Ok(Ok(output)) => Ok(output),
+        Ok(Err(e)) => Err(DeviceError::Failed(format!("could not run {}: {}", program, e))),
+        Err(_) => Err(DeviceError::Timeout),
+    }
+}
+
+pub async fn run_command_successfully(command: &[String], timeout_seconds: u64) -> DeviceResult<Output> {
+    let output = run_command(command, timeout_seconds).await?;
+
+    if output.status.success() {
+        Ok(output)
+    } else {
+        Err(DeviceError::Failed(format!(
+            "{} exited with {}: {}",
+            command.first().map(|x| x.as_str()).unwrap_or_default(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+#[derive(Debug)]
+pub struct CommandSwitch {
+    data: DeviceData,
+    config: CommandConfig,
+}
+
+impl CommandSwitch {
+    pub fn new(data: DeviceData, config: CommandConfig) -> Self {
+        Self {
+            data: DeviceData {
+                status: None,
+                ..data
+            },
+            config,
+        }
+    }
+
+    fn status_from_output(output: &Output) -> DeviceStatus {
+        match String::from_utf8_lossy(&output.stdout).trim().to_lowercase().as_str() {
+            "on" => DeviceStatus::On,
+            "off" => DeviceStatus::Off,
+            _ if output.status.success() => DeviceStatus::On,
+            _ => DeviceStatus::Off,
+        }
+    }
+}
+
+impl Switch for CommandSwitch {
+    fn turn_on(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move {
+            run_command_successfully(&self.config.on_command, self.config.timeout_seconds).await?;
+            self.data.status = Some(DeviceStatus::On);
+            Ok(())
+        })
+    }
+
+    fn turn_off(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move {
+            run_command_successfully(&self.config.off_command, self.config.timeout_seconds).await?;
+            self.data.status = Some(DeviceStatus::Off);
+            Ok(())
+        })
+    }
+
+    fn serialize(&self) -> String {
+        toml::to_string(&self.data).unwrap()
+    }
+
+    fn get_device_data(&self) -> &DeviceData {
+        &self.data
+    }
+
+    fn get_device_data_mut(&mut self) -> &mut DeviceData {
+        &mut self.data
+    }
+
+    fn update_status(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async {
+            let Some(status_command) = &self.config.status_command else {
+                return Ok(());
+            };
+
+            log::debug!("Checking switch {}'s status", self.data.alias);
+            let output = run_command(status_command, self.config.timeout_seconds).await?;
+            self.data.status = Some(Self::status_from_output(&output));
+            Ok(())
+        })
+    }
+
+    // the command gets its whole `timeout_seconds` before the poll is given up
+    fn status_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.timeout_seconds).max(STATUS_TIMEOUT)
+    }
+}
@@ -0,0 +1,98 @@
+use http::StatusCode;
+
+use super::kasa::KasaError;
+use super::sonoff::SonoffError;
+use super::webhook::HttpSwitchError;
+
+#[derive(Debug)]
+pub enum DeviceError {
+    // the device could not be contacted at all
+    Unreachable(String),
+    AuthFailed,
+    // the device answered with something we could not make sense of
+    BadResponse(String),
+    Timeout,
+    // the device (or command) was reached but refused or failed the operation
+    Failed(String),
+}
+
+impl DeviceError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            DeviceError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    // the device did not answer at all, as opposed to answering with an error
+    pub fn is_offline(&self) -> bool {
+        matches!(self, DeviceError::Unreachable(_) | DeviceError::Timeout)
+    }
+}
+
+impl std::fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceError::Unreachable(e) => write!(f, "device unreachable: {}", e),
+            DeviceError::AuthFailed => write!(f, "device refused the credentials"),
+            DeviceError::BadResponse(e) => write!(f, "bad response from device: {}", e),
+            DeviceError::Timeout => write!(f, "device timed out"),
+            DeviceError::Failed(e) => write!(f, "device operation failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+impl From<reqwest::Error> for DeviceError {
+    fn from(e: reqwest::Error) -> Self {
+        match e.status() {
+            Some(StatusCode::UNAUTHORIZED) | Some(StatusCode::FORBIDDEN) => DeviceError::AuthFailed,
+            Some(status) => DeviceError::BadResponse(format!("http status {}", status)),
+            None if e.is_timeout() => DeviceError::Timeout,
+            None if e.is_decode() => DeviceError::BadResponse(e.to_string()),
+            None => DeviceError::Unreachable(e.to_string()),
+        }
+    }
+}
+
+impl From<diqwest::error::Error> for DeviceError {
+    fn from(e: diqwest::error::Error) -> Self {
+        match e {
+            diqwest::error::Error::Reqwest(e) => e.into(),
+            diqwest::error::Error::DigestAuth(_) => DeviceError::AuthFailed,
+            e => DeviceError::BadResponse(e.to_string()),
+        }
+    }
+}
+
+impl From<SonoffError> for DeviceError {
+    fn from(e: SonoffError) -> Self {
+        match e {
+            SonoffError::Request(e) => e.into(),
+            SonoffError::Unauthorized => DeviceError::AuthFailed,
+            SonoffError::MissingOutlet(_) => DeviceError::BadResponse(e.to_string()),
+            e => DeviceError::Failed(e.to_string()),
+        }
+    }
+}
+
+impl From<HttpSwitchError> for DeviceError {
+    fn from(e: HttpSwitchError) -> Self {
+        match e {
+            HttpSwitchError::Request(e) => e.into(),
+            e => DeviceError::BadResponse(e.to_string()),
+        }
+    }
+}
+
+impl From<KasaError> for DeviceError {
+    fn from(e: KasaError) -> Self {
+        match e {
+            KasaError::Io(e) => DeviceError::Unreachable(e.to_string()),
+            KasaError::Timeout => DeviceError::Timeout,
+            KasaError::Device(..) => DeviceError::Failed(e.to_string()),
+            e => DeviceError::BadResponse(e.to_string()),
+        }
+    }
+}
@@ -2,23 +2,44 @@ use axum::{extract::{Path, State}, routing::{get, post}, Json, Router};
 use http::StatusCode;
 use serde::{Deserialize, Serialize};
 
-use crate::SafeAppState;
-
-use super::DeviceDataSafe;
+use crate::{timers::countdown::clear_countdown, SafeAppState};
+
+use super::{pulse_switch, set_switch_state, toggle_switch, DeviceDataSafe, SafeSwitch, Telemetry};
+
+// The app state lock is released before the switch is used, so requests to other devices are not held up.
+async fn find_switch(state: &SafeAppState, id: u32) -> Result<SafeSwitch, (StatusCode, String)> {
+    state
+        .read()
+        .await
+        .switches
+        .get(&id)
+        .cloned()
+        .ok_or((StatusCode::BAD_REQUEST, "Could not find any switch with the given id".to_owned()))
+}
 
+// Built from the switches' snapshots, a switch that is busy with its device shows its last known data.
 async fn get_switches(
     State(state): State<SafeAppState>,
 ) -> Result<Json<Vec<DeviceDataSafe>>, (StatusCode, String)> {
-    let mut switches: Vec<DeviceDataSafe> = Vec::new();
-
     let lock = state.read().await;
-    for switch in &lock.switches {
-        switches.push(switch.get_device_data().into());
-    }
+    let switches = lock
+        .switches
+        .values()
+        .map(|switch| {
+            let data = switch.snapshot();
+            let countdown = lock.countdowns.iter().find(|x| x.switch_id == data.id);
+            DeviceDataSafe::from(&data).with_countdown(countdown)
+        })
+        .collect();
 
     Ok(Json(switches))
 }
 
+// An explicit command replaces whatever countdown was running on the switch.
+async fn cancel_countdown(state: &SafeAppState, id: u32) {
+    clear_countdown(&mut state.write().await.countdowns, id);
+}
+
 #[derive(Serialize)]
  struct TurnOnOffResponse { success: bool }
 
@@ -26,35 +47,31 @@ async fn get_switches(
     State(state): State<SafeAppState>,
     Path(id): Path<u32>,
 ) -> Result<Json<TurnOnOffResponse>, (StatusCode, String)> {
-    let mut lock = state.write().await;
+    let switch = find_switch(&state, id).await?;
+    let mut switch = switch.lock().await;
 
-    match lock.switches.iter_mut().find(|x| x.get_device_data().id == id) {
-        Some(switch) => {
-            switch.turn_on().await;
-            Ok(Json(TurnOnOffResponse { success: true}))
-        },
-        None => Err((StatusCode::BAD_REQUEST, "Could not find any switch with the given id".to_owned())),
-    }
+    set_switch_state(switch.as_mut(), true).await.map_err(|e| (e.status_code(), e.to_string()))?;
+    cancel_countdown(&state, id).await;
+    Ok(Json(TurnOnOffResponse { success: true }))
 }
 
 async fn turn_off(
     State(state): State<SafeAppState>,
     Path(id): Path<u32>,
 ) -> Result<Json<TurnOnOffResponse>, (StatusCode, String)> {
-    let mut lock = state.write().await;
+    let switch = find_switch(&state, id).await?;
+    let mut switch = switch.lock().await;
 
-    match lock.switches.iter_mut().find(|x| x.get_device_data().id == id) {
-        Some(switch) => {
-            switch.turn_off().await;
-            Ok(Json(TurnOnOffResponse { success: true}))
-        },
-        None => Err((StatusCode::BAD_REQUEST, "Could not find any switch with the given id".to_owned())),
-    }
+    set_switch_state(switch.as_mut(), false).await.map_err(|e| (e.status_code(), e.to_string()))?;
+    cancel_countdown(&state, id).await;
+    Ok(Json(TurnOnOffResponse { success: true }))
 }
 
 #[derive(Deserialize)]
 struct ReqSwitchState {
     state: String,
+    // only keep the state for this long (e.g. to open a garage door, or heat for half an hour), then switch back
+    for_seconds: Option<f64>,
 }
 
 async fn post_switch(
@@ -62,34 +79,63 @@ async fn post_switch(
     Path(id): Path<u32>,
     Json(switch_state): Json<ReqSwitchState> 
 ) -> Result<Json<TurnOnOffResponse>, (StatusCode, String)> {
-    let mut lock = state.write().await;
-    println!("POST");
-
-    match lock.switches.iter_mut().find(|x| x.get_device_data().id == id) {
-        Some(switch) => {
-            if switch_state.state == "on".to_owned() {
-                switch.turn_on().await;
-            } else if switch_state.state == "off".to_owned() {
-                switch.turn_off().await;
-            }
-            Ok(Json(TurnOnOffResponse { success: true}))
+    let on = match switch_state.state.as_str() {
+        "on" => true,
+        "off" => false,
+        "toggle" if switch_state.for_seconds.is_none() => {
+            let switch = find_switch(&state, id).await?;
+            let mut switch = switch.lock().await;
+
+            toggle_switch(switch.as_mut()).await.map_err(|e| (e.status_code(), e.to_string()))?;
+            cancel_countdown(&state, id).await;
+            return Ok(Json(TurnOnOffResponse { success: true }));
+        },
+        "toggle" => return Err((StatusCode::BAD_REQUEST, "for_seconds can only be used with on or off".to_owned())),
+        _ => return Err((StatusCode::BAD_REQUEST, "State must be either on, off or toggle".to_owned())),
+    };
+
+    let switch = find_switch(&state, id).await?;
+    let mut switch = switch.lock().await;
+
+    match switch_state.for_seconds {
+        Some(seconds) => {
+            let duration = std::time::Duration::try_from_secs_f64(seconds)
+                .ok()
+                .filter(|x| !x.is_zero() && chrono::Duration::from_std(*x).is_ok());
+            let Some(duration) = duration else {
+                return Err((StatusCode::BAD_REQUEST, "for_seconds must be a positive number".to_owned()));
+            };
+
+            pulse_switch(&state, switch.as_mut(), on, duration).await.map_err(|e| (e.status_code(), e.to_string()))?;
+        },
+        None => {
+            set_switch_state(switch.as_mut(), on).await.map_err(|e| (e.status_code(), e.to_string()))?;
+            cancel_countdown(&state, id).await;
         },
-        None => Err((StatusCode::BAD_REQUEST, "Could not find any switch with the given id".to_owned())),
     }
+    Ok(Json(TurnOnOffResponse { success: true }))
 }
 
 async fn get_switch(
     State(state): State<SafeAppState>,
     Path(id): Path<u32>,
 ) -> Result<Json<DeviceDataSafe>, (StatusCode, String)> {
-    println!("GET");
-    let mut lock = state.write().await;
+    let switch = find_switch(&state, id).await?;
+    let data = DeviceDataSafe::from(&switch.snapshot());
 
-    match lock.switches.iter_mut().find(|x| x.get_device_data().id == id) {
-        Some(switch) => {
-            Ok(Json(switch.get_device_data().into()))
-        },
-        None => Err((StatusCode::BAD_REQUEST, "Could not find any switch with the given id".to_owned())),
+    let lock = state.read().await;
+    Ok(Json(data.with_countdown(lock.countdowns.iter().find(|x| x.switch_id == id))))
+}
+
+async fn get_switch_telemetry(
+    State(state): State<SafeAppState>,
+    Path(id): Path<u32>,
+) -> Result<Json<Telemetry>, (StatusCode, String)> {
+    let switch = find_switch(&state, id).await?;
+
+    match switch.telemetry() {
+        Some(telemetry) => Ok(Json(telemetry)),
+        None => Err((StatusCode::NOT_FOUND, "This switch does not report any telemetry".to_owned())),
     }
 }
 
@@ -100,6 +146,7 @@ pub fn add_devices_routes(state: SafeAppState) -> Router {
         .route("/api/turn_off/{id}", get(turn_off))
         .route("/api/switch/{id}", post(post_switch))
         .route("/api/switch/{id}", get(get_switch))
+        .route("/api/switch/{id}/telemetry", get(get_switch_telemetry))
         .with_state(state)
 }
 
@@ -0,0 +1,391 @@
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{timeout, Duration};
+
+use super::DeviceData;
+use super::DeviceResult;
+use super::DeviceStatus;
+use super::Switch;
+
+const KASA_PORT: u16 = 9999;
+const KASA_TIMEOUT: Duration = Duration::from_secs(5);
+// largest answer we are willing to read, HS300 sysinfo with all outlets is well below this
+const KASA_MAX_RESPONSE: usize = 64 * 1024;
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SysInfo {
+    #[serde(rename = "deviceId")]
+    pub device_id: String,
+    pub relay_state: Option<u8>,
+    #[serde(default)]
+    pub children: Vec<SysInfoChild>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SysInfoChild {
+    pub id: String,
+    pub state: u8,
+    pub alias: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum KasaError {
+    Io(std::io::Error),
+    Timeout,
+    InvalidJson(serde_json::Error),
+    Device(i64, String),
+    MissingChild(u32),
+}
+
+impl From<std::io::Error> for KasaError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl std::fmt::Display for KasaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KasaError::Io(e) => write!(f, "connection failed: {}", e),
+            KasaError::Timeout => write!(f, "the device did not answer in time"),
+            KasaError::InvalidJson(e) => write!(f, "invalid response: {}", e),
+            KasaError::Device(code, msg) => write!(f, "device error {}: {}", code, msg),
+            KasaError::MissingChild(channel) => write!(f, "the device has no outlet {}", channel),
+        }
+    }
+}
+
+/*
+* The local protocol XORs every byte with the previous ciphertext byte, starting from 171.
+*/
+fn encrypt(plain: &[u8]) -> Vec<u8> {
+    let mut key = 171u8;
+    let mut out = Vec::with_capacity(plain.len() + 4);
+    out.extend_from_slice(&(plain.len() as u32).to_be_bytes());
+    for byte in plain {
+        key ^= byte;
+        out.push(key);
+    }
+    out
+}
+
+fn decrypt(cipher: &[u8]) -> Vec<u8> {
+    let mut key = 171u8;
+    cipher
+        .iter()
+        .map(|byte| {
+            let plain = key ^ byte;
+            key = *byte;
+            plain
+        })
+        .collect()
+}
+
+/*
+* Kasa plugs (HS100/HS110/KP115/...) and power strips (HS300), where `channel` picks the child outlet.
+*/
+#[derive(Debug)]
+pub struct KasaSwitch {
+    data: DeviceData,
+    // child id prefixed with the deviceId, resolved from sysinfo the first time it is needed
+    child_id: Option<String>,
+}
+
+impl KasaSwitch {
+    pub fn new(data: DeviceData) -> Self {
+        Self {
+            data: DeviceData {
+                status: None,
+                ..data
+            },
+            child_id: None,
+        }
+    }
+
+    fn addr(&self) -> String {
+        if self.data.addr.contains(':') {
+            self.data.addr.clone()
+        } else {
+            format!("{}:{}", self.data.addr, KASA_PORT)
+        }
+    }
+
+    async fn send(&self, request: serde_json::Value) -> Result<serde_json::Value, KasaError> {
+        let exchange = async {
+            let mut stream = tokio::net::TcpStream::connect(self.addr()).await?;
+            stream.write_all(&encrypt(request.to_string().as_bytes())).await?;
+
+            let len = stream.read_u32().await? as usize;
+            if len > KASA_MAX_RESPONSE {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "response too large").into());
+            }
+            let mut buf = vec![0u8; len];
+            stream.read_exact(&mut buf).await?;
+
+            Ok::<_, KasaError>(buf)
+        };
+
+        let buf = timeout(KASA_TIMEOUT, exchange).await.map_err(|_| KasaError::Timeout)??;
+        let res: serde_json::Value = serde_json::from_slice(&decrypt(&buf)).map_err(KasaError::InvalidJson)?;
+
+        // every module answers with an err_code, anything but 0 is a failure
+        if let Some(module) = res.get("system") {
+            for (_, method) in module.as_object().into_iter().flatten() {
+                let code = method.get("err_code").and_then(|x| x.as_i64()).unwrap_or_default();
+                if code != 0 {
+                    let msg = method.get("err_msg").and_then(|x| x.as_str()).unwrap_or_default();
+                    return Err(KasaError::Device(code, msg.to_owned()));
+                }
+            }
+        }
+
+        Ok(res)
+    }
+
+    async fn get_sysinfo(&self) -> Result<SysInfo, KasaError> {
+        let res = self.send(json!({ "system": { "get_sysinfo": {} } })).await?;
+
+        serde_json::from_value(res["system"]["get_sysinfo"].clone()).map_err(KasaError::InvalidJson)
+    }
+
+    fn child_from_sysinfo(sysinfo: &SysInfo, channel: u32) -> Result<&SysInfoChild, KasaError> {
+        sysinfo
+            .children
+            .get(channel as usize)
+            .ok_or(KasaError::MissingChild(channel))
+    }
+
+    // HS300 strips report full child ids, KP303/KP400/HS107 short ones ("00", "01") that need the deviceId in front.
+    fn full_child_id(sysinfo: &SysInfo, child: &SysInfoChild) -> String {
+        if child.id.starts_with(&sysinfo.device_id) {
+            child.id.clone()
+        } else {
+            format!("{}{}", sysinfo.device_id, child.id)
+        }
+    }
+
+    async fn set(&mut self, on: bool) -> Result<(), KasaError> {
+        let mut request = json!({ "system": { "set_relay_state": { "state": on as u8 } } });
+
+        if let Some(channel) = self.data.channel {
+            if self.child_id.is_none() {
+                let sysinfo = self.get_sysinfo().await?;
+                self.child_id = Some(Self::full_child_id(&sysinfo, Self::child_from_sysinfo(&sysinfo, channel)?));
+            }
+            request["context"] = json!({ "child_ids": [self.child_id] });
+        }
+
+        self.send(request).await?;
+        self.data.status = Some(if on { DeviceStatus::On } else { DeviceStatus::Off });
+        Ok(())
+    }
+
+    async fn fetch_status(&mut self) -> Result<DeviceStatus, KasaError> {
+        let sysinfo = self.get_sysinfo().await?;
+
+        let state = match self.data.channel {
+            Some(channel) => {
+                let child = Self::child_from_sysinfo(&sysinfo, channel)?;
+                self.child_id = Some(Self::full_child_id(&sysinfo, child));
+                Some(child.state)
+            },
+            None => sysinfo.relay_state,
+        };
+
+        Ok(match state {
+            Some(1) => DeviceStatus::On,
+            Some(0) => DeviceStatus::Off,
+            _ => DeviceStatus::Unknown,
+        })
+    }
+}
+
+impl Switch for KasaSwitch {
+    fn turn_on(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move { Ok(self.set(true).await?) })
+    }
+
+    fn turn_off(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move { Ok(self.set(false).await?) })
+    }
+
+    fn serialize(&self) -> String {
+        toml::to_string(&self.data).unwrap()
+    }
+
+    fn get_device_data(&self) -> &DeviceData {
+        &self.data
+    }
+
+    fn get_device_data_mut(&mut self) -> &mut DeviceData {
+        &mut self.data
+    }
+
+    fn update_status(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async {
+            log::debug!("Checking switch {}'s status", self.data.alias);
+            self.data.status = Some(self.fetch_status().await?);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::devices::{error::DeviceError, test_utils::device_data, Device};
+
+    #[test]
+    fn encrypt_prefixes_the_length_and_decrypt_reverses_it() {
+        let plain = br#"{"system":{"get_sysinfo":{}}}"#;
+        let cipher = encrypt(plain);
+
+        assert_eq!(cipher[..4], (plain.len() as u32).to_be_bytes());
+        assert_ne!(&cipher[4..], plain);
+        assert_eq!(decrypt(&cipher[4..]), plain);
+        // first byte is XORed with the initial key
+        assert_eq!(cipher[4], 171 ^ plain[0]);
+    }
+
+    // A plug when `children` is empty, a power strip otherwise. Every request answers `err_code` when it is not 0.
+    #[derive(Default)]
+    struct FakeKasa {
+        relay_state: u8,
+        children: Vec<(String, u8)>,
+        err_code: i64,
+        // report child ids without the deviceId in front, like the KP303
+        short_child_ids: bool,
+        requests: Vec<serde_json::Value>,
+    }
+
+    impl FakeKasa {
+        fn answer(&mut self, request: serde_json::Value) -> serde_json::Value {
+            self.requests.push(request.clone());
+
+            if self.err_code != 0 {
+                return json!({ "system": { "set_relay_state": { "err_code": self.err_code, "err_msg": "module not support" } } });
+            }
+
+            if let Some(state) = request["system"]["set_relay_state"]["state"].as_u64() {
+                match request["context"]["child_ids"].as_array() {
+                    Some(ids) => {
+                        for (id, child_state) in self.children.iter_mut() {
+                            if ids.iter().any(|x| x == id.as_str()) {
+                                *child_state = state as u8;
+                            }
+                        }
+                    },
+                    None => self.relay_state = state as u8,
+                }
+                return json!({ "system": { "set_relay_state": { "err_code": 0 } } });
+            }
+
+            let children: Vec<_> = self
+                .children
+                .iter()
+                .map(|(id, state)| {
+                    let id = if self.short_child_ids { id.trim_start_matches("8006") } else { id };
+                    json!({ "id": id, "state": state, "alias": "outlet" })
+                })
+                .collect();
+            json!({ "system": { "get_sysinfo": {
+                "deviceId": "8006",
+                "relay_state": self.relay_state,
+                "children": children,
+                "err_code": 0,
+            } } })
+        }
+    }
+
+    async fn fake_kasa(fake: FakeKasa) -> (Arc<Mutex<FakeKasa>>, KasaSwitch) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let fake = Arc::new(Mutex::new(fake));
+
+        let device = fake.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let len = stream.read_u32().await.unwrap() as usize;
+                let mut buf = vec![0u8; len];
+                stream.read_exact(&mut buf).await.unwrap();
+
+                let request = serde_json::from_slice(&decrypt(&buf)).unwrap();
+                let response = device.lock().unwrap().answer(request);
+                stream.write_all(&encrypt(response.to_string().as_bytes())).await.unwrap();
+            }
+        });
+
+        (fake, KasaSwitch::new(device_data(&addr, Device::Kasa)))
+    }
+
+    #[tokio::test]
+    async fn plug_set_and_sysinfo() {
+        let (fake, mut switch) = fake_kasa(FakeKasa::default()).await;
+
+        switch.update_status().await.unwrap();
+        assert_eq!(switch.data.status, Some(DeviceStatus::Off));
+
+        switch.turn_on().await.unwrap();
+        assert_eq!(fake.lock().unwrap().relay_state, 1);
+        assert!(fake.lock().unwrap().requests[1].get("context").is_none());
+
+        switch.update_status().await.unwrap();
+        assert_eq!(switch.data.status, Some(DeviceStatus::On));
+    }
+
+    #[tokio::test]
+    async fn strip_outlets_are_addressed_by_child_id() {
+        let children = vec![("8006A0".to_owned(), 0), ("8006A1".to_owned(), 1), ("8006A2".to_owned(), 0)];
+        let (fake, mut switch) = fake_kasa(FakeKasa { children, ..Default::default() }).await;
+        switch.data.channel = Some(2);
+
+        switch.turn_on().await.unwrap();
+        {
+            let fake = fake.lock().unwrap();
+            assert_eq!(fake.children[2].1, 1);
+            assert_eq!(fake.children[0].1, 0);
+            assert_eq!(fake.requests.last().unwrap()["context"]["child_ids"], json!(["8006A2"]));
+        }
+
+        switch.data.channel = Some(1);
+        switch.child_id = None;
+        switch.update_status().await.unwrap();
+        assert_eq!(switch.data.status, Some(DeviceStatus::On));
+        assert_eq!(switch.child_id.as_deref(), Some("8006A1"));
+
+        switch.data.channel = Some(5);
+        switch.child_id = None;
+        assert!(matches!(switch.update_status().await, Err(DeviceError::BadResponse(_))));
+    }
+
+    #[tokio::test]
+    async fn short_child_ids_are_prefixed_with_the_device_id() {
+        let children = vec![("800600".to_owned(), 0), ("800601".to_owned(), 0)];
+        let (fake, mut switch) = fake_kasa(FakeKasa { children, short_child_ids: true, ..Default::default() }).await;
+        switch.data.channel = Some(1);
+
+        switch.turn_on().await.unwrap();
+        assert_eq!(fake.lock().unwrap().requests.last().unwrap()["context"]["child_ids"], json!(["800601"]));
+        assert_eq!(fake.lock().unwrap().children[1].1, 1);
+
+        switch.child_id = None;
+        switch.update_status().await.unwrap();
+        assert_eq!(switch.child_id.as_deref(), Some("800601"));
+        assert_eq!(switch.data.status, Some(DeviceStatus::On));
+    }
+
+    #[tokio::test]
+    async fn nonzero_err_code_is_a_failure() {
+        let (_, mut switch) = fake_kasa(FakeKasa { err_code: -2, ..Default::default() }).await;
+
+        match switch.turn_on().await {
+            Err(DeviceError::Failed(e)) => assert!(e.contains("-2"), "{}", e),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -1,17 +1,86 @@
-use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+
+use command::{CommandConfig, CommandSwitch};
+use error::DeviceError;
+use kasa::KasaSwitch;
+use mqtt::{MqttBroker, MqttSwitch, MqttTopics};
+use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
 use shelly::ShellySwitch;
+use shelly_gen1::ShellyGen1Switch;
+use sonoff::SonoffDiySwitch;
+use tasmota::TasmotaSwitch;
+use virtual_switch::{VirtualConfig, VirtualSwitch};
+use webhook::{HttpRequestTemplate, HttpStatusTemplate, HttpSwitch};
+use wol::{WakeOnLanConfig, WakeOnLanSwitch};
 
-use crate::{storage::get_storage_path, SafeAppState};
+use crate::{storage::get_storage_path, timers::countdown::{set_countdown, Countdown, CountdownStatus}, SafeAppState};
 
 pub mod shelly;
+pub mod shelly_gen1;
+pub mod sonoff;
+pub mod tasmota;
+pub mod virtual_switch;
+pub mod webhook;
+pub mod wol;
+pub mod command;
+pub mod error;
 pub mod http;
+pub mod kasa;
+pub mod mqtt;
+#[cfg(test)]
+pub mod test_utils;
+
+// Every switch is locked on its own, so a slow device only holds up requests for that device.
+pub type SafeSwitch = Arc<SwitchHandle>;
+
+// A device that takes longer than this to report its status is skipped until the next poll,
+// unless the switch asks for more through `Switch::status_timeout`.
+pub const STATUS_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(5);
+// Cap on any single http request to a device, on/off commands included.
+const REQUEST_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(10);
+const DEFAULT_POLL_INTERVAL_SECONDS: u64 = 5;
+// Failing devices are polled less and less often, but at least this often.
+const MAX_POLL_BACKOFF_SECONDS: u64 = 300;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(tag = "type")]
 pub enum Device {
-    Shelly,
+    Shelly {
+        #[serde(default)]
+        generation: ShellyGeneration,
+    },
+    Tasmota,
+    SonoffDiy {
+        device_id: String,
+        // deprecated, multi-outlet devices (e.g. Sonoff 4CH) pick the outlet through `channel`
+        #[serde(default, skip_serializing)]
+        outlet: Option<u32>,
+    },
+    Http {
+        on: Box<HttpRequestTemplate>,
+        off: Box<HttpRequestTemplate>,
+        status: Option<Box<HttpStatusTemplate>>,
+    },
+    Mqtt(MqttTopics),
+    WakeOnLan(WakeOnLanConfig),
+    Command(CommandConfig),
+    // HS300 outlets are picked through `channel`
+    Kasa,
+    Virtual(VirtualConfig),
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ShellyGeneration {
+    // Shelly 1, Shelly Plug S, ... (`/relay/{n}`, basic auth)
+    Gen1,
+    // Plus and Pro lines (`/rpc/Switch.*`, digest auth)
+    #[default]
+    Gen2,
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(tag = "type")]
@@ -21,12 +90,166 @@ pub enum DeviceStatus {
     Unknown,
 }
 
+pub type DeviceResult<T = ()> = Result<T, DeviceError>;
+
 pub trait Switch : Send + Sync {
-    fn turn_on(&mut self) -> futures::future::BoxFuture<()>;
-    fn turn_off(&mut self) -> futures::future::BoxFuture<()>;
-    fn update_status(&mut self) -> futures::future::BoxFuture<()>;
+    fn turn_on(&mut self) -> futures::future::BoxFuture<'_, DeviceResult>;
+    fn turn_off(&mut self) -> futures::future::BoxFuture<'_, DeviceResult>;
+    fn update_status(&mut self) -> futures::future::BoxFuture<'_, DeviceResult>;
     fn serialize(&self) -> String;
     fn get_device_data(&self) -> &DeviceData;
+    fn get_device_data_mut(&mut self) -> &mut DeviceData;
+
+    /*
+    * Switches without a native toggle are set to the opposite of their status, which is asked to the device first
+    * when it is not known.
+    */
+    fn toggle(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move {
+            if !matches!(self.get_device_data().status, Some(DeviceStatus::On | DeviceStatus::Off)) {
+                self.update_status().await?;
+            }
+
+            if self.get_device_data().status == Some(DeviceStatus::On) {
+                self.turn_off().await
+            } else {
+                self.turn_on().await
+            }
+        })
+    }
+
+    /*
+    * Sets the given state for `duration`, devices with a timer of their own are asked to switch back by themselves.
+    * Meant to be called through `pulse_switch`, whose countdown switches back the devices without one.
+    */
+    fn pulse(&mut self, on: bool, _duration: std::time::Duration) -> futures::future::BoxFuture<'_, DeviceResult> {
+        if on { self.turn_on() } else { self.turn_off() }
+    }
+
+    // Last readings of switches with metering, filled in by update_status
+    fn telemetry(&self) -> Option<&Telemetry> {
+        None
+    }
+
+    // How long a status update may take before the poll is given up
+    fn status_timeout(&self) -> tokio::time::Duration {
+        STATUS_TIMEOUT
+    }
+}
+
+/*
+* A switch along with a copy of its data and telemetry as of the last time it was unlocked. The copy can be read
+* while the switch is busy talking to the device, so listing switches never waits on a slow or dead one.
+*/
+pub struct SwitchHandle {
+    switch: tokio::sync::Mutex<Box<dyn Switch>>,
+    snapshot: std::sync::RwLock<DeviceData>,
+    telemetry: std::sync::RwLock<Option<Telemetry>>,
+}
+
+impl SwitchHandle {
+    pub fn new(switch: Box<dyn Switch>) -> Self {
+        Self {
+            snapshot: std::sync::RwLock::new(switch.get_device_data().clone()),
+            telemetry: std::sync::RwLock::new(switch.telemetry().cloned()),
+            switch: tokio::sync::Mutex::new(switch),
+        }
+    }
+
+    pub async fn lock(&self) -> SwitchGuard<'_> {
+        SwitchGuard {
+            switch: self.switch.lock().await,
+            snapshot: &self.snapshot,
+            telemetry: &self.telemetry,
+        }
+    }
+
+    pub fn snapshot(&self) -> DeviceData {
+        self.snapshot.read().unwrap().clone()
+    }
+
+    pub fn telemetry(&self) -> Option<Telemetry> {
+        self.telemetry.read().unwrap().clone()
+    }
+}
+
+// Updates the snapshot and telemetry of the switch when dropped
+pub struct SwitchGuard<'a> {
+    switch: tokio::sync::MutexGuard<'a, Box<dyn Switch>>,
+    snapshot: &'a std::sync::RwLock<DeviceData>,
+    telemetry: &'a std::sync::RwLock<Option<Telemetry>>,
+}
+
+impl Deref for SwitchGuard<'_> {
+    type Target = Box<dyn Switch>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.switch
+    }
+}
+
+impl DerefMut for SwitchGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.switch
+    }
+}
+
+impl Drop for SwitchGuard<'_> {
+    fn drop(&mut self) {
+        *self.snapshot.write().unwrap() = self.switch.get_device_data().clone();
+        *self.telemetry.write().unwrap() = self.switch.telemetry().cloned();
+    }
+}
+
+// Client for http based devices, so a device that stops answering mid-request does not hold its switch forever.
+pub fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("Could not create the http client.")
+}
+
+/*
+* Electrical readings as reported by the device, a field is None when the device has no sensor for it.
+*/
+#[derive(Serialize, Clone, Debug)]
+pub struct Telemetry {
+    pub power_w: Option<f64>,
+    pub voltage_v: Option<f64>,
+    pub current_a: Option<f64>,
+    // total energy counted by the device since its last reset
+    pub energy_wh: Option<f64>,
+    pub temperature_c: Option<f64>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/*
+* How reachable a switch has been lately, it is only kept in memory.
+*/
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Availability {
+    pub online: bool,
+    // last successful contact with the device
+    pub last_seen: Option<DateTime<Utc>>,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub last_failure: Option<DateTime<Utc>>,
+}
+
+impl Availability {
+    /*
+    * Whether a switch that keeps failing is due for another attempt, backing off from `interval_seconds`
+    * like polling does, so an unreachable device is not hammered (and logged about) every time.
+    */
+    pub fn should_retry(&self, interval_seconds: u64) -> bool {
+        match self.last_failure {
+            Some(last_failure) if self.consecutive_failures > 0 => {
+                let delay = poll_delay(interval_seconds, self.consecutive_failures - 1);
+                chrono::Duration::from_std(delay).is_ok_and(|delay| Utc::now() >= last_failure + delay)
+            },
+            _ => true,
+        }
+    }
 }
 
 /*
@@ -37,17 +260,48 @@ pub struct DeviceDataSafe
 {
     alias: String,
     id: u32,
-    #[serde(alias = "type")]
+    #[serde(alias = "type", serialize_with = "serialize_device_safe")]
     device_type: Device,
     status: Option<DeviceStatus>,
+    #[serde(flatten)]
+    availability: Availability,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    countdown: Option<CountdownStatus>,
+}
+
+impl DeviceDataSafe {
+    pub fn with_countdown(self, countdown: Option<&Countdown>) -> Self {
+        Self { countdown: countdown.map(|x| x.status()), ..self }
+    }
 }
 
 impl From<&DeviceData> for DeviceDataSafe {
     fn from(value: &DeviceData) -> Self {
-        Self { alias: value.alias.clone(), id: value.id, device_type: value.device_type.clone(), status: value.status.clone() }
+        Self {
+            alias: value.alias.clone(),
+            id: value.id,
+            device_type: value.device_type.clone(),
+            status: value.status.clone(),
+            availability: value.availability.clone(),
+            countdown: None,
+        }
     }
 }
 
+fn serialize_device_safe<S: Serializer>(device: &Device, serializer: S) -> Result<S::Ok, S::Error> {
+    // request templates and commands can carry tokens and passwords
+    let redacted_type = match device {
+        Device::Http { .. } => "Http",
+        Device::WakeOnLan(_) => "WakeOnLan",
+        Device::Command(_) => "Command",
+        _ => return device.serialize(serializer),
+    };
+
+    let mut map = serializer.serialize_map(Some(1))?;
+    map.serialize_entry("type", redacted_type)?;
+    map.end()
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DeviceData {
     pub alias: String,
@@ -55,16 +309,62 @@ pub struct DeviceData {
     pub id: u32,
     pub username: String,
     pub password: String,
+    // Relay/component index on the physical device. Several switches can share the same `addr`
+    // with different channels to expose a multi-channel device (e.g. Shelly Pro 4PM) as separate switches.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u32>,
+    // when set, the status is read back this long after each on/off command to confirm the relay followed it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify_after_ms: Option<u64>,
+    // overrides `poll_interval_seconds` from config.toml for this switch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll_interval_seconds: Option<u64>,
     #[serde(alias = "type")]
     pub device_type: Device,
     pub status: Option<DeviceStatus>,
+    #[serde(skip)]
+    pub availability: Availability,
+}
+
+impl DeviceData {
+    // switches.toml files predating `channel` used the switch id as the channel index
+    pub fn channel_or_id(&self) -> u32 {
+        self.channel.unwrap_or(self.id)
+    }
+
+    /*
+    * Keeps track of the outcome of every exchange with the device, polls and commands alike. Whatever went wrong
+    * the status could not be confirmed and becomes Unknown, the switch is only offline when the device did not answer.
+    */
+    pub fn record_result<T>(&mut self, result: &DeviceResult<T>) {
+        match result {
+            Ok(_) => {
+                self.availability = Availability {
+                    online: true,
+                    last_seen: Some(Utc::now()),
+                    consecutive_failures: 0,
+                    last_error: None,
+                    last_failure: None,
+                };
+            },
+            Err(e) => {
+                self.availability.consecutive_failures += 1;
+                self.availability.last_error = Some(e.to_string());
+                self.availability.last_failure = Some(Utc::now());
+                self.status = Some(DeviceStatus::Unknown);
+                if e.is_offline() {
+                    self.availability.online = false;
+                }
+            },
+        }
+    }
 }
 
-pub fn parse_switches_from_file() -> Vec<Box<dyn Switch>> {
+pub fn parse_switches_from_file(mqtt: &Option<Arc<MqttBroker>>) -> BTreeMap<u32, SafeSwitch> {
     let switches_toml = get_storage_path().join("switches.toml");
     log::info!("Looking for {}", switches_toml.display());
 
-    let mut out = Vec::new();
+    let mut out = BTreeMap::new();
 
     if std::path::Path::exists(&switches_toml) {
         #[derive(Deserialize)]
@@ -81,7 +381,12 @@ pub fn parse_switches_from_file() -> Vec<Box<dyn Switch>> {
         log::info!("Parsed {} switches.", switches_array.len());
 
         for switch_data in switches_array {
-            out.push(create_switch_from_data(switch_data));
+            if out.contains_key(&switch_data.id) {
+                log::warn!("Skipping switch {}: id {} is already in use.", switch_data.alias, switch_data.id);
+                continue;
+            }
+            let id = switch_data.id;
+            out.insert(id, Arc::new(SwitchHandle::new(create_switch_from_data(switch_data, mqtt))));
         }
     } else {
         log::info!("No switches.toml found.");
@@ -90,27 +395,330 @@ pub fn parse_switches_from_file() -> Vec<Box<dyn Switch>> {
     out
 }
 
-fn create_switch_from_data(device_data: DeviceData) -> Box<dyn Switch> {
-    match device_data.device_type {
-        Device::Shelly => Box::new(ShellySwitch::new(
-            device_data.alias,
-            device_data.addr,
-            device_data.id,
-            device_data.username,
-            device_data.password,
-        )),
+fn create_switch_from_data(device_data: DeviceData, mqtt: &Option<Arc<MqttBroker>>) -> Box<dyn Switch> {
+    let mut switch: Box<dyn Switch> = match device_data.device_type.clone() {
+        Device::Shelly { generation: ShellyGeneration::Gen1 } => Box::new(ShellyGen1Switch::new(device_data)),
+        Device::Shelly { generation: ShellyGeneration::Gen2 } => Box::new(ShellySwitch::new(device_data)),
+        Device::Tasmota => Box::new(TasmotaSwitch::new(device_data)),
+        Device::SonoffDiy { device_id, outlet } => {
+            let mut device_data = device_data;
+            if let Some(outlet) = outlet {
+                log::warn!("Switch {} uses the deprecated `outlet`, please set `channel = {}` instead.", device_data.alias, outlet);
+                device_data.channel.get_or_insert(outlet);
+                device_data.device_type = Device::SonoffDiy { device_id: device_id.clone(), outlet: None };
+            }
+            Box::new(SonoffDiySwitch::new(device_data, device_id))
+        },
+        Device::Http { on, off, status } => Box::new(HttpSwitch::new(device_data, on, off, status)),
+        Device::Mqtt(topics) => {
+            let broker = mqtt
+                .clone()
+                .expect("Found an mqtt switch in switches.toml but no [mqtt] section in config.toml.");
+            Box::new(MqttSwitch::new(device_data, topics, broker))
+        },
+        Device::WakeOnLan(config) => Box::new(WakeOnLanSwitch::new(device_data, config)),
+        Device::Command(config) => Box::new(CommandSwitch::new(device_data, config)),
+        Device::Kasa => Box::new(KasaSwitch::new(device_data)),
+        Device::Virtual(config) => Box::new(VirtualSwitch::new(device_data, config)),
+    };
+
+    let result = futures::executor::block_on(switch.update_status());
+    if let Err(e) = &result {
+        log::warn!("Could not retrieve switch {}'s initial status: {}", switch.get_device_data().alias, e);
     }
+    switch.get_device_data_mut().record_result(&result);
+
+    switch
 }
 
-pub async fn devices_status_task(state: SafeAppState) {
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(5));
+/*
+* Turns a switch on or off and, when the switch has `verify_after_ms`, checks that the device really ended up in that state.
+*/
+pub async fn set_switch_state(switch: &mut dyn Switch, on: bool) -> DeviceResult {
+    let result = if on { switch.turn_on().await } else { switch.turn_off().await };
+    switch.get_device_data_mut().record_result(&result);
+    result?;
+
+    verify_status(switch, if on { DeviceStatus::On } else { DeviceStatus::Off }).await
+}
+
+/*
+* Toggles a switch, the state it reports afterwards is checked like `set_switch_state` does. There is nothing to check
+* when the switch can not tell which state it went to.
+*/
+pub async fn toggle_switch(switch: &mut dyn Switch) -> DeviceResult {
+    let result = switch.toggle().await;
+    switch.get_device_data_mut().record_result(&result);
+    result?;
+
+    match switch.get_device_data().status.clone() {
+        Some(status @ (DeviceStatus::On | DeviceStatus::Off)) => verify_status(switch, status).await,
+        _ => Ok(()),
+    }
+}
+
+/*
+* Sets the given state for `duration`. The server keeps a countdown that restores the opposite state once it is over,
+* so the switch comes back even when the device has no timer of its own (or lost it, e.g. after a reboot).
+*/
+pub async fn pulse_switch(state: &SafeAppState, switch: &mut dyn Switch, on: bool, duration: std::time::Duration) -> DeviceResult {
+    let deadline = chrono::Duration::from_std(duration)
+        .ok()
+        .and_then(|x| Utc::now().checked_add_signed(x))
+        .ok_or_else(|| DeviceError::Failed(format!("{:?} is too long for a pulse", duration)))?;
+
+    let result = switch.pulse(on, duration).await;
+    switch.get_device_data_mut().record_result(&result);
+    result?;
+
+    set_countdown(&mut state.write().await.countdowns, Countdown {
+        switch_id: switch.get_device_data().id,
+        then_on: !on,
+        deadline,
+    });
+
+    verify_pulse(switch, on, duration).await
+}
+
+// The state is checked like `set_switch_state` does, unless the pulse is over before `verify_after_ms`.
+async fn verify_pulse(switch: &mut dyn Switch, on: bool, duration: std::time::Duration) -> DeviceResult {
+    if switch.get_device_data().verify_after_ms.is_some_and(|x| u128::from(x) >= duration.as_millis()) {
+        return Ok(());
+    }
+    verify_status(switch, if on { DeviceStatus::On } else { DeviceStatus::Off }).await
+}
+
+// When the switch has `verify_after_ms`, reads its status back after that delay and fails unless it is `expected`.
+async fn verify_status(switch: &mut dyn Switch, expected: DeviceStatus) -> DeviceResult {
+    let Some(verify_after_ms) = switch.get_device_data().verify_after_ms else {
+        return Ok(());
+    };
+
+    tokio::time::sleep(tokio::time::Duration::from_millis(verify_after_ms)).await;
+    let result = switch.update_status().await;
+    switch.get_device_data_mut().record_result(&result);
+    result?;
+
+    match &switch.get_device_data().status {
+        Some(status) if *status == expected => Ok(()),
+        status => Err(DeviceError::Failed(format!(
+            "expected {:?} but the device reports {:?} after {}ms",
+            expected, status, verify_after_ms
+        ))),
+    }
+}
+
+async fn poll_switch(switch: &SafeSwitch) -> DeviceResult {
+    let mut switch = switch.lock().await;
+
+    let status_timeout = switch.status_timeout();
+    let result = tokio::time::timeout(status_timeout, switch.update_status())
+        .await
+        .unwrap_or(Err(DeviceError::Timeout));
+
+    switch.get_device_data_mut().record_result(&result);
+    result
+}
 
+/*
+* Doubles the poll interval for every consecutive failure, up to MAX_POLL_BACKOFF_SECONDS
+* (or the interval itself when that is longer).
+*/
+fn poll_delay(interval_seconds: u64, consecutive_failures: u32) -> tokio::time::Duration {
+    let delay = interval_seconds
+        .saturating_mul(1u64 << consecutive_failures.min(16))
+        .min(MAX_POLL_BACKOFF_SECONDS.max(interval_seconds));
+
+    tokio::time::Duration::from_secs(delay)
+}
+
+async fn switch_status_task(switch: SafeSwitch, default_interval_seconds: u64) {
     loop {
-        let num_switches = state.read().await.switches.len();
-        for i in 0..num_switches {
-            let mut lock = state.write().await;
-            lock.switches.get_mut(i).unwrap().update_status().await;
+        let was_failing = switch.snapshot().availability.consecutive_failures > 0;
+        let result = poll_switch(&switch).await;
+
+        let (alias, interval_seconds, consecutive_failures) = {
+            let data = switch.snapshot();
+            (
+                data.alias.clone(),
+                data.poll_interval_seconds.unwrap_or(default_interval_seconds).max(1),
+                data.availability.consecutive_failures,
+            )
+        };
+
+        match result {
+            Ok(()) => {
+                if was_failing {
+                    log::info!("Switch {} is reachable again.", alias);
+                }
+            },
+            Err(e) => {
+                log::warn!(
+                    "There was an error while retrieving switch {}'s status: {}. Next attempt in {:?}.",
+                    alias, e, poll_delay(interval_seconds, consecutive_failures)
+                );
+            },
         }
-        interval.tick().await;
+
+        tokio::time::sleep(poll_delay(interval_seconds, consecutive_failures)).await;
+    }
+}
+
+/*
+* Polls every switch concurrently, each on its own schedule. The app state is only read to get the list of switches,
+* each device is then locked on its own for the duration of its request.
+*/
+pub async fn devices_status_task(state: SafeAppState) {
+    let (switches, default_interval_seconds) = {
+        let lock = state.read().await;
+        let switches: Vec<SafeSwitch> = lock.switches.values().cloned().collect();
+        (switches, lock.config.poll_interval_seconds.unwrap_or(DEFAULT_POLL_INTERVAL_SECONDS))
+    };
+
+    futures::future::join_all(
+        switches
+            .into_iter()
+            .map(|switch| switch_status_task(switch, default_interval_seconds))
+    )
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_utils::device_data;
+
+    fn virtual_switch(latency_ms: u64) -> SafeSwitch {
+        let config = VirtualConfig { latency_ms, ..Default::default() };
+        Arc::new(SwitchHandle::new(Box::new(VirtualSwitch::new(device_data("", Device::Virtual(config.clone())), config))))
+    }
+
+    // A relay that acknowledges every command, but only follows them when it is not `stuck`
+    struct FakeRelay {
+        data: DeviceData,
+        relay_on: bool,
+        stuck: bool,
+    }
+
+    impl FakeRelay {
+        fn new(relay_on: bool, stuck: bool, verify_after_ms: Option<u64>) -> Self {
+            Self { data: DeviceData { verify_after_ms, ..device_data("", Device::Tasmota) }, relay_on, stuck }
+        }
+
+        fn set(&mut self, on: bool) -> futures::future::BoxFuture<'_, DeviceResult> {
+            Box::pin(async move {
+                if !self.stuck {
+                    self.relay_on = on;
+                }
+                self.data.status = Some(if on { DeviceStatus::On } else { DeviceStatus::Off });
+                Ok(())
+            })
+        }
+    }
+
+    impl Switch for FakeRelay {
+        fn turn_on(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+            self.set(true)
+        }
+
+        fn turn_off(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+            self.set(false)
+        }
+
+        fn update_status(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+            Box::pin(async move {
+                self.data.status = Some(if self.relay_on { DeviceStatus::On } else { DeviceStatus::Off });
+                Ok(())
+            })
+        }
+
+        fn serialize(&self) -> String {
+            toml::to_string(&self.data).unwrap()
+        }
+
+        fn get_device_data(&self) -> &DeviceData {
+            &self.data
+        }
+
+        fn get_device_data_mut(&mut self) -> &mut DeviceData {
+            &mut self.data
+        }
+    }
+
+    #[test]
+    fn failures_make_the_status_unknown_but_only_silence_makes_it_offline() {
+        let mut data = device_data("", Device::Tasmota);
+        data.status = Some(DeviceStatus::On);
+        data.record_result(&Ok(()));
+
+        data.record_result::<()>(&Err(DeviceError::BadResponse("garbage".to_owned())));
+        assert_eq!(data.status, Some(DeviceStatus::Unknown));
+        assert!(data.availability.online);
+        assert_eq!(data.availability.consecutive_failures, 1);
+
+        data.record_result::<()>(&Err(DeviceError::Timeout));
+        assert!(!data.availability.online);
+        assert_eq!(data.availability.consecutive_failures, 2);
+
+        data.record_result(&Ok(()));
+        assert!(data.availability.online);
+        assert_eq!(data.availability.consecutive_failures, 0);
+        assert_eq!(data.availability.last_error, None);
+    }
+
+    #[tokio::test]
+    async fn snapshot_does_not_wait_for_a_busy_switch() {
+        let switch = virtual_switch(60_000);
+
+        let busy = switch.clone();
+        let command = tokio::spawn(async move { busy.lock().await.turn_on().await });
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+        assert!(switch.switch.try_lock().is_err());
+        assert_eq!(switch.snapshot().status, Some(DeviceStatus::Off));
+        command.abort();
+    }
+
+    #[tokio::test]
+    async fn snapshot_is_updated_when_the_switch_is_unlocked() {
+        let switch = virtual_switch(0);
+
+        set_switch_state(switch.lock().await.as_mut(), true).await.unwrap();
+
+        let snapshot = switch.snapshot();
+        assert_eq!(snapshot.status, Some(DeviceStatus::On));
+        assert!(snapshot.availability.online);
+    }
+
+    #[tokio::test]
+    async fn toggling_an_unknown_status_asks_the_device_first() {
+        let mut switch = FakeRelay::new(true, false, None);
+
+        toggle_switch(&mut switch).await.unwrap();
+
+        assert!(!switch.relay_on);
+        assert_eq!(switch.data.status, Some(DeviceStatus::Off));
+    }
+
+    #[tokio::test]
+    async fn toggles_and_pulses_are_read_back() {
+        let minute = std::time::Duration::from_secs(60);
+
+        let mut switch = FakeRelay::new(false, false, Some(1));
+        toggle_switch(&mut switch).await.unwrap();
+        switch.pulse(false, minute).await.unwrap();
+        verify_pulse(&mut switch, false, minute).await.unwrap();
+        assert!(!switch.relay_on);
+
+        let mut switch = FakeRelay::new(false, true, Some(1));
+        switch.data.status = Some(DeviceStatus::Off);
+        assert!(matches!(toggle_switch(&mut switch).await, Err(DeviceError::Failed(_))));
+        switch.pulse(true, minute).await.unwrap();
+        assert!(matches!(verify_pulse(&mut switch, true, minute).await, Err(DeviceError::Failed(_))));
+
+        // a pulse that is over by the time the status is read back is not checked
+        let mut switch = FakeRelay::new(false, true, Some(2_000));
+        switch.pulse(true, std::time::Duration::from_secs(1)).await.unwrap();
+        verify_pulse(&mut switch, true, std::time::Duration::from_secs(1)).await.unwrap();
     }
 }
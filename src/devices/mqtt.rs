@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use rumqttc::{AsyncClient, ConnectReturnCode, Event, EventLoop, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::config::MqttConfig;
+use crate::SafeAppState;
+
+use super::webhook::json_path_to_pointer;
+use super::DeviceData;
+use super::DeviceError;
+use super::DeviceResult;
+use super::DeviceStatus;
+use super::SafeSwitch;
+use super::Switch;
+
+// Requests are queued for the event loop, a full queue (e.g. a stalled broker) must not hold the switch forever.
+const PUBLISH_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(5);
+
+/*
+* Topics and payloads of an mqtt switch.
+* Defaults match Tasmota/ESPHome style `ON`/`OFF` payloads, Zigbee2MQTT devices usually need
+* `payload_on = '{"state":"ON"}'` and `state_json_path = "$.state"`.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MqttTopics {
+    pub command_topic: String,
+    pub state_topic: Option<String>,
+    #[serde(default = "default_payload_on")]
+    pub payload_on: String,
+    #[serde(default = "default_payload_off")]
+    pub payload_off: String,
+    // values received on `state_topic`, they default to `payload_on`/`payload_off`
+    pub state_on: Option<String>,
+    pub state_off: Option<String>,
+    pub state_json_path: Option<String>,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+fn default_payload_on() -> String {
+    "ON".to_owned()
+}
+
+fn default_payload_off() -> String {
+    "OFF".to_owned()
+}
+
+/*
+* Shared broker connection. Every received publish is cached by topic so switches can read their state
+* without any round trip, the cache is filled by `mqtt_task` as messages come in and the switches
+* subscribed to the topic are updated right away.
+*/
+pub struct MqttBroker {
+    client: AsyncClient,
+    // switch ids by state topic
+    subscribers: std::sync::RwLock<HashMap<String, Vec<u32>>>,
+    states: std::sync::RwLock<HashMap<String, String>>,
+    connected: AtomicBool,
+    event_loop: std::sync::Mutex<Option<EventLoop>>,
+}
+
+impl MqttBroker {
+    pub fn new(config: &MqttConfig) -> Self {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(std::time::Duration::from_secs(30));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+
+        let (client, event_loop) = AsyncClient::new(options, 100);
+
+        Self {
+            client,
+            subscribers: std::sync::RwLock::new(HashMap::new()),
+            states: std::sync::RwLock::new(HashMap::new()),
+            connected: AtomicBool::new(false),
+            event_loop: std::sync::Mutex::new(Some(event_loop)),
+        }
+    }
+
+    // Topics are (re)subscribed every time the connection is established.
+    pub fn subscribe(&self, topic: &str, switch_id: u32) {
+        self.subscribers.write().unwrap().entry(topic.to_owned()).or_default().push(switch_id);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    fn ensure_connected(&self) -> DeviceResult {
+        if self.is_connected() {
+            Ok(())
+        } else {
+            Err(DeviceError::Unreachable("not connected to the mqtt broker".to_owned()))
+        }
+    }
+
+    pub fn get_state(&self, topic: &str) -> Option<String> {
+        self.states
+            .read()
+            .unwrap()
+            .get(topic)
+            .filter(|x| !x.is_empty())
+            .cloned()
+    }
+
+    pub async fn publish(&self, topic: &str, payload: &str, retain: bool) -> DeviceResult {
+        self.ensure_connected()?;
+
+        tokio::time::timeout(PUBLISH_TIMEOUT, self.client.publish(topic, QoS::AtLeastOnce, retain, payload.as_bytes().to_vec()))
+            .await
+            .map_err(|_| DeviceError::Timeout)?
+            .map_err(|e| DeviceError::Unreachable(format!("could not publish to {}: {}", topic, e)))
+    }
+
+    fn subscribe_all(&self) {
+        for topic in self.subscribers.read().unwrap().keys() {
+            if let Err(e) = self.client.try_subscribe(topic, QoS::AtLeastOnce) {
+                log::warn!("Could not subscribe to mqtt topic {}: {:?}", topic, e);
+            }
+        }
+    }
+}
+
+/*
+* Runs the broker connection. A message on a state topic updates the switches subscribed to it, each in a task
+* of its own so a switch that is busy with a command does not hold up the event loop that command waits on.
+*/
+pub async fn mqtt_task(broker: Arc<MqttBroker>, state: SafeAppState) {
+    let Some(mut event_loop) = broker.event_loop.lock().unwrap().take() else {
+        log::warn!("The mqtt event loop is already running.");
+        return;
+    };
+
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(ack))) if ack.code == ConnectReturnCode::Success => {
+                log::info!("Connected to mqtt broker.");
+                broker.connected.store(true, Ordering::Relaxed);
+                broker.subscribe_all();
+            },
+            Ok(Event::Incoming(Packet::ConnAck(ack))) => {
+                log::warn!("The mqtt broker refused the connection: {:?}", ack.code);
+            },
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let payload = String::from_utf8_lossy(&publish.payload).to_string();
+                log::debug!("Received {} on mqtt topic {}", payload, publish.topic);
+                broker.states.write().unwrap().insert(publish.topic.clone(), payload);
+
+                let switch_ids = broker.subscribers.read().unwrap().get(&publish.topic).cloned().unwrap_or_default();
+                let switches: Vec<SafeSwitch> = {
+                    let lock = state.read().await;
+                    switch_ids.iter().filter_map(|id| lock.switches.get(id).cloned()).collect()
+                };
+                for switch in switches {
+                    tokio::spawn(async move {
+                        let mut switch = switch.lock().await;
+                        let result = switch.update_status().await;
+                        switch.get_device_data_mut().record_result(&result);
+                    });
+                }
+            },
+            Ok(_) => {},
+            Err(e) => {
+                broker.connected.store(false, Ordering::Relaxed);
+                log::warn!("Lost connection to mqtt broker: {:?}. Retrying in 5 seconds.", e);
+                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+            },
+        }
+    }
+}
+
+pub struct MqttSwitch {
+    data: DeviceData,
+    topics: MqttTopics,
+    broker: Arc<MqttBroker>,
+}
+
+impl MqttSwitch {
+    pub fn new(data: DeviceData, topics: MqttTopics, broker: Arc<MqttBroker>) -> Self {
+        if let Some(state_topic) = &topics.state_topic {
+            broker.subscribe(state_topic, data.id);
+        }
+
+        Self {
+            data: DeviceData {
+                status: None,
+                ..data
+            },
+            topics,
+            broker,
+        }
+    }
+
+    fn status_from_payload(&self, payload: &str) -> DeviceStatus {
+        let value = match &self.topics.state_json_path {
+            Some(path) => {
+                let value = serde_json::from_str::<serde_json::Value>(payload)
+                    .ok()
+                    .zip(json_path_to_pointer(path))
+                    .and_then(|(json, pointer)| json.pointer(&pointer).cloned());
+                match value {
+                    Some(serde_json::Value::String(s)) => s,
+                    Some(other) => other.to_string(),
+                    None => return DeviceStatus::Unknown,
+                }
+            },
+            None => payload.to_owned(),
+        };
+
+        let state_on = self.topics.state_on.as_ref().unwrap_or(&self.topics.payload_on);
+        let state_off = self.topics.state_off.as_ref().unwrap_or(&self.topics.payload_off);
+
+        if value.trim() == state_on {
+            DeviceStatus::On
+        } else if value.trim() == state_off {
+            DeviceStatus::Off
+        } else {
+            DeviceStatus::Unknown
+        }
+    }
+
+    async fn publish(&mut self, on: bool) -> DeviceResult {
+        let payload = if on { &self.topics.payload_on } else { &self.topics.payload_off };
+
+        self.broker.publish(&self.topics.command_topic, payload, self.topics.retain).await?;
+
+        // the state topic, when there is one, will confirm it later on
+        self.data.status = Some(if on { DeviceStatus::On } else { DeviceStatus::Off });
+        Ok(())
+    }
+}
+
+impl Switch for MqttSwitch {
+    fn turn_on(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(self.publish(true))
+    }
+
+    fn turn_off(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(self.publish(false))
+    }
+
+    fn serialize(&self) -> String {
+        toml::to_string(&self.data).unwrap()
+    }
+
+    fn get_device_data(&self) -> &DeviceData {
+        &self.data
+    }
+
+    fn get_device_data_mut(&mut self) -> &mut DeviceData {
+        &mut self.data
+    }
+
+    fn update_status(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async {
+            // without a connection nothing that is cached can be trusted anymore
+            self.broker.ensure_connected()?;
+
+            let Some(state_topic) = &self.topics.state_topic else {
+                return Ok(());
+            };
+
+            if let Some(payload) = self.broker.get_state(state_topic) {
+                self.data.status = Some(self.status_from_payload(&payload));
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::future::Future;
+    use std::sync::Mutex;
+
+    use bytes::BytesMut;
+    use rumqttc::{ConnAck, PingResp, PubAck, Publish, SubAck, SubscribeReasonCode};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::{mpsc, RwLock};
+
+    use super::*;
+    use crate::devices::{test_utils::device_data, Device, SwitchHandle};
+    use crate::AppState;
+
+    /*
+    * Just enough of an MQTT 3.1.1 broker for a single client: publishes from the client are recorded, the ones sent
+    * through `inject` are forwarded to it. Dropping `inject` closes the connection.
+    */
+    struct FakeBroker {
+        port: u16,
+        received: Arc<Mutex<Vec<(String, String)>>>,
+        inject: mpsc::UnboundedSender<Publish>,
+    }
+
+    async fn fake_broker() -> FakeBroker {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let (inject, mut injected) = mpsc::unbounded_channel::<Publish>();
+
+        let broker_received = received.clone();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = BytesMut::new();
+
+            loop {
+                let mut out = BytesMut::new();
+                tokio::select! {
+                    read = socket.read_buf(&mut buffer) => {
+                        if read.unwrap() == 0 {
+                            return;
+                        }
+                        while let Ok(packet) = rumqttc::mqttbytes::v4::read(&mut buffer, 1 << 20) {
+                            match packet {
+                                Packet::Connect(_) => ConnAck::new(ConnectReturnCode::Success, false).write(&mut out),
+                                Packet::Subscribe(subscribe) => SubAck::new(
+                                    subscribe.pkid,
+                                    subscribe.filters.iter().map(|_| SubscribeReasonCode::Success(QoS::AtLeastOnce)).collect(),
+                                ).write(&mut out),
+                                Packet::Publish(publish) => {
+                                    let payload = String::from_utf8_lossy(&publish.payload).to_string();
+                                    broker_received.lock().unwrap().push((publish.topic, payload));
+                                    PubAck::new(publish.pkid).write(&mut out)
+                                },
+                                Packet::PingReq => PingResp.write(&mut out),
+                                _ => Ok(0),
+                            }
+                            .unwrap();
+                        }
+                    },
+                    publish = injected.recv() => match publish {
+                        Some(publish) => { publish.write(&mut out).unwrap(); },
+                        None => return,
+                    },
+                }
+                socket.write_all(&out).await.unwrap();
+            }
+        });
+
+        FakeBroker { port, received, inject }
+    }
+
+    async fn wait_for<F: Future<Output = bool>>(condition: impl Fn() -> F) {
+        for _ in 0..500 {
+            if condition().await {
+                return;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+        panic!("condition not met in time");
+    }
+
+    fn zigbee_topics() -> MqttTopics {
+        MqttTopics {
+            command_topic: "zigbee2mqtt/plug/set".to_owned(),
+            state_topic: Some("zigbee2mqtt/plug".to_owned()),
+            payload_on: r#"{"state":"ON"}"#.to_owned(),
+            payload_off: r#"{"state":"OFF"}"#.to_owned(),
+            state_on: Some("ON".to_owned()),
+            state_off: Some("OFF".to_owned()),
+            state_json_path: Some("$.state".to_owned()),
+            retain: false,
+        }
+    }
+
+    // A switch on its own broker connection, `mqtt_task` is only started when `connect` is true
+    async fn mqtt_switch(port: u16, connect: bool) -> (Arc<MqttBroker>, SafeSwitch) {
+        let broker = Arc::new(MqttBroker::new(&MqttConfig {
+            host: "127.0.0.1".to_owned(),
+            port,
+            username: None,
+            password: None,
+            client_id: "test".to_owned(),
+        }));
+        let topics = zigbee_topics();
+        let switch: SafeSwitch = Arc::new(SwitchHandle::new(Box::new(MqttSwitch::new(
+            device_data("", Device::Mqtt(topics.clone())),
+            topics,
+            broker.clone(),
+        ))));
+
+        if connect {
+            let state = Arc::new(RwLock::new(AppState { switches: BTreeMap::from([(1, switch.clone())]), ..Default::default() }));
+            tokio::spawn(mqtt_task(broker.clone(), state));
+            wait_for(|| async { broker.is_connected() }).await;
+        }
+
+        (broker, switch)
+    }
+
+    #[tokio::test]
+    async fn commands_fail_while_disconnected() {
+        let (_, switch) = mqtt_switch(1, false).await;
+        let mut switch = switch.lock().await;
+
+        assert!(matches!(switch.turn_on().await, Err(DeviceError::Unreachable(_))));
+        assert!(matches!(switch.update_status().await, Err(DeviceError::Unreachable(_))));
+        assert_eq!(switch.get_device_data().status, None);
+    }
+
+    #[tokio::test]
+    async fn commands_are_published() {
+        let fake = fake_broker().await;
+        let (_, switch) = mqtt_switch(fake.port, true).await;
+
+        switch.lock().await.turn_on().await.unwrap();
+        wait_for(|| async { !fake.received.lock().unwrap().is_empty() }).await;
+
+        assert_eq!(
+            fake.received.lock().unwrap()[0],
+            ("zigbee2mqtt/plug/set".to_owned(), r#"{"state":"ON"}"#.to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn state_messages_update_the_switch() {
+        let fake = fake_broker().await;
+        let (_, switch) = mqtt_switch(fake.port, true).await;
+
+        for (payload, status) in [(r#"{"state":"ON","linkquality":80}"#, DeviceStatus::On), (r#"{"state":"OFF"}"#, DeviceStatus::Off)] {
+            fake.inject.send(Publish::new("zigbee2mqtt/plug", QoS::AtMostOnce, payload)).unwrap();
+            wait_for(|| async { switch.lock().await.get_device_data().status == Some(status.clone()) }).await;
+        }
+        assert!(switch.lock().await.get_device_data().availability.online);
+    }
+
+    #[tokio::test]
+    async fn losing_the_broker_makes_the_switch_unreachable() {
+        let fake = fake_broker().await;
+        let (broker, switch) = mqtt_switch(fake.port, true).await;
+
+        drop(fake.inject);
+        wait_for(|| async { !broker.is_connected() }).await;
+
+        let mut switch = switch.lock().await;
+        assert!(matches!(switch.update_status().await, Err(DeviceError::Unreachable(_))));
+        assert!(matches!(switch.turn_off().await, Err(DeviceError::Unreachable(_))));
+    }
+}
@@ -2,10 +2,16 @@ use diqwest::WithDigestAuth;
 use serde::Deserialize;
 use serde::Serialize;
 
-use super::Device;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use super::http_client;
 use super::DeviceData;
+use super::DeviceResult;
 use super::DeviceStatus;
 use super::Switch;
+use super::Telemetry;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -13,88 +19,109 @@ pub struct GetStatusResponse {
     pub id: i64,
     pub source: String,
     pub output: bool,
-    pub apower: f64,
-    pub voltage: f64,
-    pub current: f64,
-    pub aenergy: GetStatusResponseEnergy,
-    pub temperature: GetStatusTemperature,
+    // metering fields are only there on devices with a power meter (Plus 1PM, Plus Plug S, Pro 4PM, ...)
+    pub apower: Option<f64>,
+    pub voltage: Option<f64>,
+    pub current: Option<f64>,
+    pub aenergy: Option<GetStatusResponseEnergy>,
+    pub temperature: Option<GetStatusTemperature>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GetStatusResponseEnergy {
     pub total: f64,
-    #[serde(rename = "by_minute")]
+    #[serde(rename = "by_minute", default)]
     pub by_minute: Vec<f64>,
-    #[serde(rename = "minute_ts")]
+    #[serde(rename = "minute_ts", default)]
     pub minute_ts: f64,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GetStatusTemperature {
-    pub t_c: f64,
-    pub t_f: f64,
+    pub t_c: Option<f64>,
+    pub t_f: Option<f64>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SetResponse {
+    pub was_on: bool,
 }
 
 #[derive(Debug)]
 pub struct ShellySwitch {
     data: DeviceData,
     client: reqwest::Client,
+    telemetry: Option<Telemetry>,
 }
 
 unsafe impl Send for ShellySwitch {}
 
 
 impl ShellySwitch {
-    pub fn new(alias: String, addr: String, id: u32, username: String, password: String) -> Self {
-        let mut s = Self {
+    pub fn new(data: DeviceData) -> Self {
+        Self {
             data: DeviceData {
-                alias,
-                addr,
-                id,
-                username,
-                password,
-                device_type: Device::Shelly,
                 status: None,
+                ..data
             },
-            client: reqwest::Client::new(),
-        };
+            client: http_client(),
+            telemetry: None,
+        }
+    }
 
-        futures::executor::block_on(s.update_status());
+    /*
+    * Calls a `Switch.*` rpc method on the configured channel, `params` are appended to the query string.
+    * The status is only updated once the device acknowledged the command with a `was_on` reply,
+    * error codes (e.g. 401 on wrong credentials) are reported as failures.
+    */
+    async fn switch_rpc(&self, method: &str, params: &str) -> DeviceResult<SetResponse> {
+        let res = self.client
+            .get(format!(
+                "http://{}/rpc/Switch.{}?id={}{}",
+                self.data.addr, method, self.data.channel_or_id(), params
+            ))
+            .send_with_digest_auth(&self.data.username, &self.data.password)
+            .await?
+            .error_for_status()?
+            .json::<SetResponse>()
+            .await?;
 
-        s
+        log::debug!("Switch {} was_on: {}", self.data.alias, res.was_on);
+        Ok(res)
+    }
+
+    async fn set(&mut self, on: bool) -> DeviceResult {
+        self.switch_rpc("Set", &format!("&on={}", on)).await?;
+        self.data.status = Some(if on { DeviceStatus::On } else { DeviceStatus::Off });
+        Ok(())
     }
 }
 
 impl Switch for ShellySwitch {
-    fn turn_on(&mut self) -> futures::future::BoxFuture<()> {
+    fn turn_on(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(self.set(true))
+    }
+
+    fn turn_off(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(self.set(false))
+    }
+
+    fn toggle(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
         Box::pin(async move {
-            match self.client
-                .get(format!(
-                    "http://{}/rpc/Switch.Set?id={}&on=true",
-                    self.data.addr, self.data.id
-                ))
-                .send_with_digest_auth(&self.data.username, &self.data.password)
-                .await {
-                    Ok(_r) => { self.data.status = Some(DeviceStatus::On); },
-                    Err(e) => { log::warn!("There was an error while trying to turn on shelly {}: {:?}", self.data.alias, e)},
-                }
+            let res = self.switch_rpc("Toggle", "").await?;
+            self.data.status = Some(if res.was_on { DeviceStatus::Off } else { DeviceStatus::On });
+            Ok(())
         })
     }
 
-    fn turn_off(&mut self) -> futures::future::BoxFuture<()> {
+    fn pulse(&mut self, on: bool, duration: Duration) -> futures::future::BoxFuture<'_, DeviceResult> {
         Box::pin(async move {
-            match self.client
-                .get(format!(
-                    "http://{}/rpc/Switch.Set?id={}&on=false",
-                    self.data.addr, self.data.id
-                ))
-                .send_with_digest_auth(&self.data.username, &self.data.password)
-                .await {
-                    Ok(_r) => { self.data.status = Some(DeviceStatus::Off); },
-                    Err(e) => { log::warn!("There was an error while trying to turn off shelly {}: {:?}", self.data.alias, e)},
-                }
+            // the device flips the output back by itself once `toggle_after` seconds have passed
+            self.switch_rpc("Set", &format!("&on={}&toggle_after={}", on, duration.as_secs_f64())).await?;
+            self.data.status = Some(if on { DeviceStatus::On } else { DeviceStatus::Off });
+            Ok(())
         })
     }
 
@@ -105,31 +132,90 @@ impl Switch for ShellySwitch {
     fn get_device_data(&self) -> &DeviceData {
         &self.data
     }
+
+    fn get_device_data_mut(&mut self) -> &mut DeviceData {
+        &mut self.data
+    }
     
-    fn update_status(&mut self) -> futures::future::BoxFuture<()> {
+    fn update_status(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
         Box::pin(async {
             log::debug!("Checking switch {}'s status", self.data.alias);
-            if let Ok(res) = self.client
-            .get(format!(
-                "http://{}/rpc/Switch.GetStatus?id={}",
-                self.data.addr, self.data.id
-            ))
-            .send_with_digest_auth(&self.data.username, &self.data.password)
-            .await {
-                match res.json::<GetStatusResponse>().await {
-                    Ok(status) => {
-                        if status.output {
-                            self.data.status = Some(super::DeviceStatus::On);
-                        } else {
-                            self.data.status = Some(super::DeviceStatus::Off);
-                        }
-                    },
-                    Err(e) => {
-                        log::warn!("There was an error while retrieving switch {}'s status: {:?}", self.data.alias, e);
-                    },
-                }
+            let status = self.client
+                .get(format!(
+                    "http://{}/rpc/Switch.GetStatus?id={}",
+                    self.data.addr, self.data.channel_or_id()
+                ))
+                .send_with_digest_auth(&self.data.username, &self.data.password)
+                .await?
+                .error_for_status()?
+                .json::<GetStatusResponse>()
+                .await?;
+
+            if status.output {
+                self.data.status = Some(super::DeviceStatus::On);
+            } else {
+                self.data.status = Some(super::DeviceStatus::Off);
+            }
+
+            // devices without a power meter (Plus 1) report no telemetry, like Gen1 ones without meters
+            if status.apower.is_some() || status.aenergy.is_some() {
+                self.telemetry = Some(Telemetry {
+                    power_w: status.apower,
+                    voltage_v: status.voltage,
+                    current_a: status.current,
+                    energy_wh: status.aenergy.map(|x| x.total),
+                    temperature_c: status.temperature.and_then(|x| x.t_c),
+                    timestamp: Utc::now(),
+                });
             }
+            Ok(())
         })
     }
+
+    fn telemetry(&self) -> Option<&Telemetry> {
+        self.telemetry.as_ref()
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use axum::{routing::get, Json, Router};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::devices::{test_utils::{device_data, serve}, Device, ShellyGeneration};
+
+    async fn shelly(status: Value) -> ShellySwitch {
+        let router = Router::new().route("/rpc/Switch.GetStatus", get(move || async move { Json(status) }));
+        let addr = serve(router).await;
+        ShellySwitch::new(device_data(&addr, Device::Shelly { generation: ShellyGeneration::Gen2 }))
+    }
+
+    #[tokio::test]
+    async fn switches_without_a_meter_report_no_telemetry() {
+        let mut switch = shelly(json!({ "id": 0, "source": "init", "output": true, "temperature": { "tC": 40.0 } })).await;
+
+        switch.update_status().await.unwrap();
+
+        assert_eq!(switch.get_device_data().status, Some(DeviceStatus::On));
+        assert!(switch.telemetry().is_none());
+    }
+
+    #[tokio::test]
+    async fn metered_switches_report_telemetry() {
+        let mut switch = shelly(json!({
+            "id": 0, "source": "init", "output": false,
+            "apower": 12.5, "voltage": 230.1, "current": 0.05,
+            "aenergy": { "total": 1234.5 },
+            "temperature": { "tC": 40.0 }
+        })).await;
+
+        switch.update_status().await.unwrap();
+
+        assert_eq!(switch.get_device_data().status, Some(DeviceStatus::Off));
+        let telemetry = switch.telemetry().unwrap();
+        assert_eq!(telemetry.power_w, Some(12.5));
+        assert_eq!(telemetry.energy_wh, Some(1234.5));
+        assert_eq!(telemetry.temperature_c, Some(40.0));
+    }
+}
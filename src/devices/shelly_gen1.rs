@@ -0,0 +1,170 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::http_client;
+use super::DeviceData;
+use super::DeviceError;
+use super::DeviceResult;
+use super::DeviceStatus;
+use super::Switch;
+use super::Telemetry;
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RelayResponse {
+    pub ison: bool,
+    #[serde(default)]
+    pub has_timer: bool,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatusResponse {
+    #[serde(default)]
+    pub relays: Vec<RelayResponse>,
+    #[serde(default)]
+    pub meters: Vec<StatusResponseMeter>,
+    pub temperature: Option<f64>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatusResponseMeter {
+    pub power: f64,
+    // watt-minutes
+    pub total: Option<f64>,
+}
+
+// `channel` picks the relay, the first one when it is not set
+#[derive(Debug)]
+pub struct ShellyGen1Switch {
+    data: DeviceData,
+    client: reqwest::Client,
+    telemetry: Option<Telemetry>,
+}
+
+impl ShellyGen1Switch {
+    pub fn new(data: DeviceData) -> Self {
+        Self {
+            data: DeviceData {
+                status: None,
+                ..data
+            },
+            client: http_client(),
+            telemetry: None,
+        }
+    }
+
+    /*
+    * Gen1 devices only ask for credentials when "restrict login" is enabled, and use plain basic auth for it.
+    */
+    fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        let req = self.client.get(format!("http://{}{}", self.data.addr, path));
+
+        if self.data.username.is_empty() {
+            req
+        } else {
+            req.basic_auth(&self.data.username, Some(&self.data.password))
+        }
+    }
+
+    // With a `timer` (in seconds) the relay flips back by itself once it expires.
+    async fn set_relay(&self, turn: &str, timer: Option<u64>) -> Result<RelayResponse, reqwest::Error> {
+        let mut query = vec![("turn", turn.to_owned())];
+        if let Some(timer) = timer {
+            query.push(("timer", timer.to_string()));
+        }
+
+        self.get(&format!("/relay/{}", self.data.channel.unwrap_or_default()))
+            .query(&query)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<RelayResponse>()
+            .await
+    }
+}
+
+impl Switch for ShellyGen1Switch {
+    fn turn_on(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move {
+            let r = self.set_relay("on", None).await?;
+            self.data.status = Some(if r.ison { DeviceStatus::On } else { DeviceStatus::Off });
+            Ok(())
+        })
+    }
+
+    fn turn_off(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move {
+            let r = self.set_relay("off", None).await?;
+            self.data.status = Some(if r.ison { DeviceStatus::On } else { DeviceStatus::Off });
+            Ok(())
+        })
+    }
+
+    fn toggle(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move {
+            let r = self.set_relay("toggle", None).await?;
+            self.data.status = Some(if r.ison { DeviceStatus::On } else { DeviceStatus::Off });
+            Ok(())
+        })
+    }
+
+    fn pulse(&mut self, on: bool, duration: Duration) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move {
+            let r = self.set_relay(if on { "on" } else { "off" }, Some(duration.as_secs_f64().ceil() as u64)).await?;
+            self.data.status = Some(if r.ison { DeviceStatus::On } else { DeviceStatus::Off });
+            Ok(())
+        })
+    }
+
+    fn serialize(&self) -> String {
+        toml::to_string(&self.data).unwrap()
+    }
+
+    fn get_device_data(&self) -> &DeviceData {
+        &self.data
+    }
+
+    fn get_device_data_mut(&mut self) -> &mut DeviceData {
+        &mut self.data
+    }
+
+    fn update_status(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async {
+            log::debug!("Checking switch {}'s status", self.data.alias);
+            let status = self.get("/status")
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<StatusResponse>()
+                .await?;
+
+            let channel = self.data.channel.unwrap_or_default();
+            match status.relays.get(channel as usize) {
+                Some(relay) if relay.ison => { self.data.status = Some(DeviceStatus::On); },
+                Some(_) => { self.data.status = Some(DeviceStatus::Off); },
+                None => {
+                    return Err(DeviceError::BadResponse(format!("relay {} not reported", channel)));
+                },
+            }
+
+            // devices without a power meter (Shelly 1) report no meters at all
+            if let Some(meter) = status.meters.get(channel as usize) {
+                self.telemetry = Some(Telemetry {
+                    power_w: Some(meter.power),
+                    voltage_v: None,
+                    current_a: None,
+                    energy_wh: meter.total.map(|x| x / 60.0),
+                    temperature_c: status.temperature,
+                    timestamp: Utc::now(),
+                });
+            }
+            Ok(())
+        })
+    }
+
+    fn telemetry(&self) -> Option<&Telemetry> {
+        self.telemetry.as_ref()
+    }
+}
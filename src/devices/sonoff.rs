@@ -0,0 +1,186 @@
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+
+use super::http_client;
+use super::DeviceData;
+use super::DeviceResult;
+use super::DeviceStatus;
+use super::Switch;
+
+const DEFAULT_DIY_PORT: u16 = 8081;
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ZeroconfResponse {
+    pub seq: Option<u64>,
+    pub error: i64,
+    pub data: Option<ZeroconfInfo>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ZeroconfInfo {
+    pub switch: Option<String>,
+    pub switches: Option<Vec<ZeroconfOutlet>>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ZeroconfOutlet {
+    pub switch: String,
+    pub outlet: u32,
+}
+
+#[derive(Debug)]
+pub enum SonoffError {
+    Request(reqwest::Error),
+    // error codes as documented by the Sonoff DIY API
+    InvalidRequest,
+    Unauthorized,
+    DeviceNotFound,
+    InvalidParameters,
+    Unknown(i64),
+    MissingOutlet(u32),
+}
+
+impl From<reqwest::Error> for SonoffError {
+    fn from(value: reqwest::Error) -> Self {
+        Self::Request(value)
+    }
+}
+
+impl From<i64> for SonoffError {
+    fn from(code: i64) -> Self {
+        match code {
+            400 => Self::InvalidRequest,
+            401 => Self::Unauthorized,
+            404 => Self::DeviceNotFound,
+            422 => Self::InvalidParameters,
+            code => Self::Unknown(code),
+        }
+    }
+}
+
+impl std::fmt::Display for SonoffError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SonoffError::Request(e) => write!(f, "request failed: {}", e),
+            SonoffError::InvalidRequest => write!(f, "the request was not formatted correctly"),
+            SonoffError::Unauthorized => write!(f, "the request was unauthorized (is encryption enabled?)"),
+            SonoffError::DeviceNotFound => write!(f, "no device with the given deviceid"),
+            SonoffError::InvalidParameters => write!(f, "the request contained invalid parameters"),
+            SonoffError::Unknown(code) => write!(f, "unknown error code {}", code),
+            SonoffError::MissingOutlet(outlet) => write!(f, "the device did not report outlet {}", outlet),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SonoffDiySwitch {
+    data: DeviceData,
+    device_id: String,
+    client: reqwest::Client,
+}
+
+impl SonoffDiySwitch {
+    // `channel` is the outlet of multi-outlet devices (e.g. Sonoff 4CH), single outlet devices leave it unset
+    pub fn new(data: DeviceData, device_id: String) -> Self {
+        Self {
+            data: DeviceData {
+                status: None,
+                ..data
+            },
+            device_id,
+            client: http_client(),
+        }
+    }
+
+    fn url(&self, endpoint: &str) -> String {
+        if self.data.addr.contains(':') {
+            format!("http://{}/zeroconf/{}", self.data.addr, endpoint)
+        } else {
+            format!("http://{}:{}/zeroconf/{}", self.data.addr, DEFAULT_DIY_PORT, endpoint)
+        }
+    }
+
+    async fn post(&self, endpoint: &str, data: serde_json::Value) -> Result<ZeroconfResponse, SonoffError> {
+        let res = self.client
+            .post(self.url(endpoint))
+            .json(&json!({ "deviceid": self.device_id, "data": data }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<ZeroconfResponse>()
+            .await?;
+
+        match res.error {
+            0 => Ok(res),
+            code => Err(code.into()),
+        }
+    }
+
+    async fn set(&mut self, on: bool) -> Result<(), SonoffError> {
+        let state = if on { "on" } else { "off" };
+
+        match self.data.channel {
+            Some(outlet) => {
+                self.post("switches", json!({ "switches": [{ "switch": state, "outlet": outlet }] })).await?;
+            },
+            None => {
+                self.post("switch", json!({ "switch": state })).await?;
+            },
+        }
+
+        self.data.status = Some(if on { DeviceStatus::On } else { DeviceStatus::Off });
+        Ok(())
+    }
+
+    async fn fetch_status(&self) -> Result<DeviceStatus, SonoffError> {
+        let info = self.post("info", json!({})).await?.data.unwrap_or_default();
+
+        let state = match self.data.channel {
+            Some(outlet) => info
+                .switches
+                .unwrap_or_default()
+                .into_iter()
+                .find(|x| x.outlet == outlet)
+                .map(|x| x.switch)
+                .ok_or(SonoffError::MissingOutlet(outlet))?,
+            None => info.switch.unwrap_or_default(),
+        };
+
+        Ok(match state.as_str() {
+            "on" => DeviceStatus::On,
+            "off" => DeviceStatus::Off,
+            _ => DeviceStatus::Unknown,
+        })
+    }
+}
+
+impl Switch for SonoffDiySwitch {
+    fn turn_on(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move { Ok(self.set(true).await?) })
+    }
+
+    fn turn_off(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move { Ok(self.set(false).await?) })
+    }
+
+    fn serialize(&self) -> String {
+        toml::to_string(&self.data).unwrap()
+    }
+
+    fn get_device_data(&self) -> &DeviceData {
+        &self.data
+    }
+
+    fn get_device_data_mut(&mut self) -> &mut DeviceData {
+        &mut self.data
+    }
+
+    fn update_status(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async {
+            log::debug!("Checking switch {}'s status", self.data.alias);
+            self.data.status = Some(self.fetch_status().await?);
+            Ok(())
+        })
+    }
+}
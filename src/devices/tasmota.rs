@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+
+use super::http_client;
+use super::DeviceData;
+use super::DeviceError;
+use super::DeviceResult;
+use super::DeviceStatus;
+use super::Switch;
+
+// e.g. {"POWER":"ON"} or, on multi-relay devices, {"POWER2":"OFF"}
+pub type PowerResponse = HashMap<String, String>;
+
+#[derive(Debug)]
+pub struct TasmotaSwitch {
+    data: DeviceData,
+    client: reqwest::Client,
+}
+
+impl TasmotaSwitch {
+    pub fn new(data: DeviceData) -> Self {
+        Self {
+            data: DeviceData {
+                status: None,
+                ..data
+            },
+            client: http_client(),
+        }
+    }
+
+    /*
+    * Sends a `Power` command (optionally with a parameter, e.g. `On`) through the `cm` endpoint.
+    * Credentials are only sent along when a username has been configured.
+    * Tasmota relays are 1-indexed while `channel` is 0-indexed like for every other device, so channel 0 is `Power1`
+    * (`Power0` would switch all the relays at once).
+    */
+    async fn send_power_command(&self, param: Option<&str>) -> DeviceResult<DeviceStatus> {
+        let command = match self.data.channel {
+            Some(channel) => format!("Power{}", channel + 1),
+            None => "Power".to_owned(),
+        };
+        let cmnd = match param {
+            Some(param) => format!("{} {}", command, param),
+            None => command.clone(),
+        };
+
+        let mut query = vec![("cmnd", cmnd.as_str())];
+        if !self.data.username.is_empty() {
+            query.push(("user", &self.data.username));
+            query.push(("password", &self.data.password));
+        }
+
+        let res = self.client
+            .get(format!("http://{}/cm", self.data.addr))
+            .query(&query)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<PowerResponse>()
+            .await?;
+
+        // single relay devices answer with a plain `POWER` even when asked for `Power1`
+        let power = res
+            .get(&command.to_uppercase())
+            .or_else(|| res.get("POWER"))
+            .map(|x| x.as_str());
+
+        match power {
+            Some("ON") => Ok(DeviceStatus::On),
+            Some("OFF") => Ok(DeviceStatus::Off),
+            Some(_) => Ok(DeviceStatus::Unknown),
+            // wrong or missing credentials are answered with {"WARNING":"Need user=<username>&password=<password>"}
+            None if res.contains_key("WARNING") => Err(DeviceError::AuthFailed),
+            None => Err(DeviceError::BadResponse(format!("no {} in {:?}", command, res))),
+        }
+    }
+}
+
+impl Switch for TasmotaSwitch {
+    fn turn_on(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move {
+            self.data.status = Some(self.send_power_command(Some("On")).await?);
+            Ok(())
+        })
+    }
+
+    fn turn_off(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move {
+            self.data.status = Some(self.send_power_command(Some("Off")).await?);
+            Ok(())
+        })
+    }
+
+    fn toggle(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move {
+            self.data.status = Some(self.send_power_command(Some("Toggle")).await?);
+            Ok(())
+        })
+    }
+
+    fn serialize(&self) -> String {
+        toml::to_string(&self.data).unwrap()
+    }
+
+    fn get_device_data(&self) -> &DeviceData {
+        &self.data
+    }
+
+    fn get_device_data_mut(&mut self) -> &mut DeviceData {
+        &mut self.data
+    }
+
+    fn update_status(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async {
+            log::debug!("Checking switch {}'s status", self.data.alias);
+            self.data.status = Some(self.send_power_command(None).await?);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{extract::{Query, State}, http::StatusCode, response::{IntoResponse, Response}, routing::get, Json, Router};
+
+    use super::*;
+    use crate::devices::{test_utils::{device_data, serve}, Device};
+
+    // Answers `/cm` like a Tasmota device with `relays.len()` relays
+    struct FakeTasmota {
+        relays: Mutex<Vec<bool>>,
+        password: Option<String>,
+        status_code: StatusCode,
+    }
+
+    async fn handle_cm(State(fake): State<Arc<FakeTasmota>>, Query(query): Query<HashMap<String, String>>) -> Response {
+        if fake.status_code != StatusCode::OK {
+            return fake.status_code.into_response();
+        }
+        if fake.password.is_some() && query.get("password") != fake.password.as_ref() {
+            return Json(HashMap::from([("WARNING", "Need user=<username>&password=<password>")])).into_response();
+        }
+
+        let cmnd = &query["cmnd"];
+        let (command, param) = cmnd.split_once(' ').unwrap_or((cmnd, ""));
+        let index: usize = command.trim_start_matches("Power").parse().unwrap_or(1);
+
+        let mut relays = fake.relays.lock().unwrap();
+        let relay = &mut relays[index - 1];
+        match param {
+            "On" => *relay = true,
+            "Off" => *relay = false,
+            "Toggle" => *relay = !*relay,
+            _ => {},
+        }
+
+        let key = if relays.len() == 1 { "POWER".to_owned() } else { command.to_uppercase() };
+        Json(HashMap::from([(key, if relays[index - 1] { "ON" } else { "OFF" })])).into_response()
+    }
+
+    async fn fake_tasmota(relays: usize, password: Option<&str>, status_code: StatusCode) -> (Arc<FakeTasmota>, TasmotaSwitch) {
+        let fake = Arc::new(FakeTasmota {
+            relays: Mutex::new(vec![false; relays]),
+            password: password.map(|x| x.to_owned()),
+            status_code,
+        });
+        let addr = serve(Router::new().route("/cm", get(handle_cm)).with_state(fake.clone())).await;
+        (fake, TasmotaSwitch::new(device_data(&addr, Device::Tasmota)))
+    }
+
+    #[tokio::test]
+    async fn power_on_off_and_status() {
+        let (fake, mut switch) = fake_tasmota(1, None, StatusCode::OK).await;
+
+        switch.update_status().await.unwrap();
+        assert_eq!(switch.data.status, Some(DeviceStatus::Off));
+
+        switch.turn_on().await.unwrap();
+        assert_eq!(switch.data.status, Some(DeviceStatus::On));
+        assert!(fake.relays.lock().unwrap()[0]);
+
+        switch.turn_off().await.unwrap();
+        assert_eq!(switch.data.status, Some(DeviceStatus::Off));
+        assert!(!fake.relays.lock().unwrap()[0]);
+
+        switch.toggle().await.unwrap();
+        assert_eq!(switch.data.status, Some(DeviceStatus::On));
+    }
+
+    #[tokio::test]
+    async fn multi_relay_devices_answer_with_the_relay_key() {
+        let (fake, mut switch) = fake_tasmota(2, None, StatusCode::OK).await;
+        switch.data.channel = Some(1);
+
+        switch.turn_on().await.unwrap();
+        assert_eq!(switch.data.status, Some(DeviceStatus::On));
+        assert_eq!(*fake.relays.lock().unwrap(), vec![false, true]);
+    }
+
+    #[tokio::test]
+    async fn single_relay_devices_answer_with_power() {
+        let (_, mut switch) = fake_tasmota(1, None, StatusCode::OK).await;
+        switch.data.channel = Some(0);
+
+        switch.turn_on().await.unwrap();
+        assert_eq!(switch.data.status, Some(DeviceStatus::On));
+    }
+
+    #[tokio::test]
+    async fn warning_is_auth_failed() {
+        let (fake, mut switch) = fake_tasmota(1, Some("secret"), StatusCode::OK).await;
+
+        assert!(matches!(switch.turn_on().await, Err(DeviceError::AuthFailed)));
+        assert!(!fake.relays.lock().unwrap()[0]);
+
+        switch.data.username = "admin".to_owned();
+        switch.data.password = "secret".to_owned();
+        switch.turn_on().await.unwrap();
+        assert!(fake.relays.lock().unwrap()[0]);
+    }
+
+    #[tokio::test]
+    async fn non_2xx_responses_are_errors() {
+        let (_, mut switch) = fake_tasmota(1, None, StatusCode::INTERNAL_SERVER_ERROR).await;
+        assert!(matches!(switch.update_status().await, Err(DeviceError::BadResponse(_))));
+
+        let (_, mut switch) = fake_tasmota(1, None, StatusCode::UNAUTHORIZED).await;
+        assert!(matches!(switch.turn_on().await, Err(DeviceError::AuthFailed)));
+    }
+}
@@ -0,0 +1,25 @@
+use super::{Availability, Device, DeviceData};
+
+pub fn device_data(addr: &str, device_type: Device) -> DeviceData {
+    DeviceData {
+        alias: "test".to_owned(),
+        addr: addr.to_owned(),
+        id: 1,
+        username: String::new(),
+        password: String::new(),
+        channel: None,
+        verify_after_ms: None,
+        poll_interval_seconds: None,
+        device_type,
+        status: None,
+        availability: Availability::default(),
+    }
+}
+
+// Serves `router` on a random local port, returns the `host:port` to put in `addr`.
+pub async fn serve(router: axum::Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    addr.to_string()
+}
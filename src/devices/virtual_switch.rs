@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use rand::Rng;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::storage::get_storage_path;
+
+use super::DeviceData;
+use super::DeviceError;
+use super::DeviceResult;
+use super::DeviceStatus;
+use super::Switch;
+
+/*
+* A switch that only exists in memory, for trying timers and the UI out without real relays.
+* `latency_ms` delays every operation and `failure_rate` (0.0 - 1.0) makes that share of them fail.
+* With `persist` the state is kept in virtual_switches.toml across restarts.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct VirtualConfig {
+    #[serde(default)]
+    pub latency_ms: u64,
+    #[serde(default)]
+    pub failure_rate: f64,
+    #[serde(default)]
+    pub persist: bool,
+}
+
+// For toml serialization purposes, switch id -> is on
+#[derive(Serialize, Deserialize, Default)]
+struct VirtualStates {
+    states: HashMap<String, bool>,
+}
+
+fn virtual_states_path() -> std::path::PathBuf {
+    get_storage_path().join("virtual_switches.toml")
+}
+
+fn load_virtual_states() -> VirtualStates {
+    std::fs::read_to_string(virtual_states_path())
+        .ok()
+        .and_then(|x| toml::from_str(&x).ok())
+        .unwrap_or_default()
+}
+
+#[derive(Debug)]
+pub struct VirtualSwitch {
+    data: DeviceData,
+    config: VirtualConfig,
+    is_on: bool,
+}
+
+impl VirtualSwitch {
+    pub fn new(data: DeviceData, config: VirtualConfig) -> Self {
+        let is_on = config.persist
+            && load_virtual_states()
+                .states
+                .get(&data.id.to_string())
+                .copied()
+                .unwrap_or_default();
+
+        Self {
+            data: DeviceData {
+                status: Some(if is_on { DeviceStatus::On } else { DeviceStatus::Off }),
+                ..data
+            },
+            config,
+            is_on,
+        }
+    }
+
+    // Waits for the configured latency, then tells whether the operation should fail.
+    async fn simulate(&self) -> bool {
+        if self.config.latency_ms > 0 {
+            tokio::time::sleep(tokio::time::Duration::from_millis(self.config.latency_ms)).await;
+        }
+
+        self.config.failure_rate > 0.0 && rand::thread_rng().gen_bool(self.config.failure_rate.min(1.0))
+    }
+
+    fn store(&self) {
+        let mut states = load_virtual_states();
+        states.states.insert(self.data.id.to_string(), self.is_on);
+
+        if let Err(e) = std::fs::write(virtual_states_path(), toml::to_string(&states).expect("Could not serialize virtual switches states.")) {
+            log::warn!("Could not write virtual switch {}'s state: {:?}", self.data.alias, e);
+        }
+    }
+
+    async fn set(&mut self, on: bool) -> DeviceResult {
+        if self.simulate().await {
+            return Err(DeviceError::Unreachable("simulated failure".to_owned()));
+        }
+
+        self.is_on = on;
+        self.data.status = Some(if on { DeviceStatus::On } else { DeviceStatus::Off });
+
+        if self.config.persist {
+            self.store();
+        }
+        Ok(())
+    }
+}
+
+impl Switch for VirtualSwitch {
+    fn turn_on(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(self.set(true))
+    }
+
+    fn turn_off(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(self.set(false))
+    }
+
+    fn serialize(&self) -> String {
+        toml::to_string(&self.data).unwrap()
+    }
+
+    fn get_device_data(&self) -> &DeviceData {
+        &self.data
+    }
+
+    fn get_device_data_mut(&mut self) -> &mut DeviceData {
+        &mut self.data
+    }
+
+    fn update_status(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async {
+            if self.simulate().await {
+                return Err(DeviceError::Unreachable("simulated failure".to_owned()));
+            }
+
+            self.data.status = Some(if self.is_on { DeviceStatus::On } else { DeviceStatus::Off });
+            Ok(())
+        })
+    }
+}
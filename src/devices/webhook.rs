@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::http_client;
+use super::DeviceData;
+use super::DeviceResult;
+use super::DeviceStatus;
+use super::Switch;
+
+/*
+* A single request as described in switches.toml.
+* `url`, `body` and header values can reference `{addr}`, `{channel}`, `{username}` and `{password}`.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct HttpRequestTemplate {
+    #[serde(default = "default_method")]
+    pub method: String,
+    pub url: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub body: Option<String>,
+}
+
+/*
+* The status request plus how to read the switch state out of its response.
+* `json_path` (e.g. `$.relays[0].ison`) takes precedence over `regex`, whose first capture group (or the whole match) is used.
+* The extracted value is compared against `on_value`/`off_value`, when those are missing common truthy/falsy values are accepted.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct HttpStatusTemplate {
+    #[serde(flatten)]
+    pub request: HttpRequestTemplate,
+    pub json_path: Option<String>,
+    pub regex: Option<String>,
+    pub on_value: Option<String>,
+    pub off_value: Option<String>,
+}
+
+fn default_method() -> String {
+    "GET".to_owned()
+}
+
+#[derive(Debug)]
+pub enum HttpSwitchError {
+    Request(reqwest::Error),
+    InvalidMethod(String),
+    InvalidJson(serde_json::Error),
+    InvalidJsonPath(String),
+    InvalidRegex(regex::Error),
+    NoMatch,
+}
+
+impl From<reqwest::Error> for HttpSwitchError {
+    fn from(value: reqwest::Error) -> Self {
+        Self::Request(value)
+    }
+}
+
+impl std::fmt::Display for HttpSwitchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpSwitchError::Request(e) => write!(f, "request failed: {}", e),
+            HttpSwitchError::InvalidMethod(method) => write!(f, "invalid http method {}", method),
+            HttpSwitchError::InvalidJson(e) => write!(f, "response is not valid json: {}", e),
+            HttpSwitchError::InvalidJsonPath(path) => write!(f, "unsupported json path {}", path),
+            HttpSwitchError::InvalidRegex(e) => write!(f, "invalid regex: {}", e),
+            HttpSwitchError::NoMatch => write!(f, "could not find the status in the response"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpSwitch {
+    data: DeviceData,
+    on: Box<HttpRequestTemplate>,
+    off: Box<HttpRequestTemplate>,
+    status: Option<Box<HttpStatusTemplate>>,
+    client: reqwest::Client,
+}
+
+impl HttpSwitch {
+    pub fn new(data: DeviceData, on: Box<HttpRequestTemplate>, off: Box<HttpRequestTemplate>, status: Option<Box<HttpStatusTemplate>>) -> Self {
+        Self {
+            data: DeviceData {
+                status: None,
+                ..data
+            },
+            on,
+            off,
+            status,
+            client: http_client(),
+        }
+    }
+
+    fn fill_template(&self, template: &str) -> String {
+        template
+            .replace("{addr}", &self.data.addr)
+            .replace("{channel}", &self.data.channel.unwrap_or_default().to_string())
+            .replace("{username}", &self.data.username)
+            .replace("{password}", &self.data.password)
+    }
+
+    async fn send(&self, template: &HttpRequestTemplate) -> Result<String, HttpSwitchError> {
+        let method = reqwest::Method::from_bytes(template.method.to_uppercase().as_bytes())
+            .map_err(|_| HttpSwitchError::InvalidMethod(template.method.clone()))?;
+
+        let mut req = self.client.request(method, self.fill_template(&template.url));
+        for (name, value) in &template.headers {
+            req = req.header(name, self.fill_template(value));
+        }
+        if let Some(body) = &template.body {
+            req = req.body(self.fill_template(body));
+        }
+
+        Ok(req.send().await?.error_for_status()?.text().await?)
+    }
+
+    async fn fetch_status(&self, template: &HttpStatusTemplate) -> Result<DeviceStatus, HttpSwitchError> {
+        let res = self.send(&template.request).await?;
+
+        let value = if let Some(path) = &template.json_path {
+            let json: serde_json::Value = serde_json::from_str(&res).map_err(HttpSwitchError::InvalidJson)?;
+            let pointer = json_path_to_pointer(path).ok_or(HttpSwitchError::InvalidJsonPath(path.clone()))?;
+            match json.pointer(&pointer).ok_or(HttpSwitchError::NoMatch)? {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            }
+        } else if let Some(regex) = &template.regex {
+            let regex = regex::Regex::new(regex).map_err(HttpSwitchError::InvalidRegex)?;
+            let captures = regex.captures(&res).ok_or(HttpSwitchError::NoMatch)?;
+            captures
+                .get(1)
+                .or_else(|| captures.get(0))
+                .map(|x| x.as_str().to_owned())
+                .unwrap_or_default()
+        } else {
+            res
+        };
+
+        Ok(status_from_value(value.trim(), template))
+    }
+}
+
+fn status_from_value(value: &str, template: &HttpStatusTemplate) -> DeviceStatus {
+    match (&template.on_value, &template.off_value) {
+        (Some(on), _) if on == value => DeviceStatus::On,
+        (_, Some(off)) if off == value => DeviceStatus::Off,
+        (Some(_), Some(_)) => DeviceStatus::Unknown,
+        // only on_value was given, anything else means off
+        (Some(_), None) => DeviceStatus::Off,
+        (None, Some(_)) => DeviceStatus::On,
+        (None, None) => match value.to_lowercase().as_str() {
+            "true" | "on" | "1" => DeviceStatus::On,
+            "false" | "off" | "0" => DeviceStatus::Off,
+            _ => DeviceStatus::Unknown,
+        },
+    }
+}
+
+/*
+* Only the dotted subset of JSONPath is supported: `$.a.b[0].c` becomes the JSON pointer `/a/b/0/c`.
+*/
+pub fn json_path_to_pointer(path: &str) -> Option<String> {
+    let path = path.strip_prefix('$').unwrap_or(path);
+    let mut pointer = String::new();
+
+    for segment in path.split('.').filter(|x| !x.is_empty()) {
+        let (key, mut indexes) = match segment.find('[') {
+            Some(i) => segment.split_at(i),
+            None => (segment, ""),
+        };
+
+        if !key.is_empty() {
+            pointer.push('/');
+            pointer.push_str(&key.replace('~', "~0").replace('/', "~1"));
+        }
+
+        while !indexes.is_empty() {
+            let end = indexes.find(']')?;
+            let index = indexes.get(1..end)?;
+            index.parse::<usize>().ok()?;
+            pointer.push('/');
+            pointer.push_str(index);
+            indexes = &indexes[end + 1..];
+            if !indexes.is_empty() && !indexes.starts_with('[') {
+                return None;
+            }
+        }
+    }
+
+    Some(pointer)
+}
+
+impl Switch for HttpSwitch {
+    fn turn_on(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move {
+            self.send(&self.on).await?;
+            self.data.status = Some(DeviceStatus::On);
+            Ok(())
+        })
+    }
+
+    fn turn_off(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move {
+            self.send(&self.off).await?;
+            self.data.status = Some(DeviceStatus::Off);
+            Ok(())
+        })
+    }
+
+    fn serialize(&self) -> String {
+        toml::to_string(&self.data).unwrap()
+    }
+
+    fn get_device_data(&self) -> &DeviceData {
+        &self.data
+    }
+
+    fn get_device_data_mut(&mut self) -> &mut DeviceData {
+        &mut self.data
+    }
+
+    fn update_status(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async {
+            // without a status request the last commanded state is all we know
+            let Some(template) = &self.status else {
+                return Ok(());
+            };
+
+            log::debug!("Checking switch {}'s status", self.data.alias);
+            self.data.status = Some(self.fetch_status(template).await?);
+            Ok(())
+        })
+    }
+}
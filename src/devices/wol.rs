@@ -0,0 +1,159 @@
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::time::{timeout, Duration, Instant};
+
+use super::command::run_command_successfully;
+use super::DeviceData;
+use super::DeviceError;
+use super::DeviceResult;
+use super::DeviceStatus;
+use super::Switch;
+
+/*
+* A computer seen as a switch: woken up with a magic packet, shut down by running `shutdown_command`
+* (e.g. `["ssh", "nas", "sudo", "poweroff"]`) and considered on while `addr:probe_port` accepts tcp connections.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WakeOnLanConfig {
+    pub mac: String,
+    #[serde(default = "default_broadcast")]
+    pub broadcast: String,
+    #[serde(default)]
+    pub shutdown_command: Vec<String>,
+    #[serde(default = "default_probe_port")]
+    pub probe_port: u16,
+    #[serde(default = "default_command_timeout_seconds")]
+    pub command_timeout_seconds: u64,
+    // booting up or shutting down takes a while, probes disagreeing with the last command are ignored for this long
+    #[serde(default = "default_transition_seconds")]
+    pub transition_seconds: u64,
+}
+
+fn default_broadcast() -> String {
+    "255.255.255.255:9".to_owned()
+}
+
+fn default_probe_port() -> u16 {
+    22
+}
+
+fn default_command_timeout_seconds() -> u64 {
+    30
+}
+
+fn default_transition_seconds() -> u64 {
+    180
+}
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub struct WakeOnLanSwitch {
+    data: DeviceData,
+    config: WakeOnLanConfig,
+    // last commanded status and when it was sent
+    transition: Option<(DeviceStatus, Instant)>,
+}
+
+impl WakeOnLanSwitch {
+    pub fn new(data: DeviceData, config: WakeOnLanConfig) -> Self {
+        Self {
+            data: DeviceData {
+                status: None,
+                ..data
+            },
+            config,
+            transition: None,
+        }
+    }
+
+    fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+        let bytes = mac
+            .split([':', '-'])
+            .map(|x| u8::from_str_radix(x, 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .ok()?;
+
+        bytes.try_into().ok()
+    }
+
+    async fn send_magic_packet(&self) -> std::io::Result<()> {
+        let mac = Self::parse_mac(&self.config.mac).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid mac address {}", self.config.mac))
+        })?;
+
+        let mut packet = vec![0xFF; 6];
+        for _ in 0..16 {
+            packet.extend_from_slice(&mac);
+        }
+
+        let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+        socket.set_broadcast(true)?;
+        socket.send_to(&packet, &self.config.broadcast).await?;
+
+        Ok(())
+    }
+
+    async fn probe(&self) -> DeviceStatus {
+        let addr = format!("{}:{}", self.data.addr, self.config.probe_port);
+
+        match timeout(PROBE_TIMEOUT, tokio::net::TcpStream::connect(&addr)).await {
+            Ok(Ok(_)) => DeviceStatus::On,
+            _ => DeviceStatus::Off,
+        }
+    }
+}
+
+impl Switch for WakeOnLanSwitch {
+    fn turn_on(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move {
+            self.send_magic_packet()
+                .await
+                .map_err(|e| DeviceError::Failed(format!("could not send magic packet: {}", e)))?;
+
+            self.data.status = Some(DeviceStatus::On);
+            self.transition = Some((DeviceStatus::On, Instant::now()));
+            Ok(())
+        })
+    }
+
+    fn turn_off(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async move {
+            run_command_successfully(&self.config.shutdown_command, self.config.command_timeout_seconds).await?;
+
+            self.data.status = Some(DeviceStatus::Off);
+            self.transition = Some((DeviceStatus::Off, Instant::now()));
+            Ok(())
+        })
+    }
+
+    fn serialize(&self) -> String {
+        toml::to_string(&self.data).unwrap()
+    }
+
+    fn get_device_data(&self) -> &DeviceData {
+        &self.data
+    }
+
+    fn get_device_data_mut(&mut self) -> &mut DeviceData {
+        &mut self.data
+    }
+
+    fn update_status(&mut self) -> futures::future::BoxFuture<'_, DeviceResult> {
+        Box::pin(async {
+            log::debug!("Checking switch {}'s status", self.data.alias);
+            let status = self.probe().await;
+
+            if let Some((expected, since)) = &self.transition {
+                let in_transition = since.elapsed() < Duration::from_secs(self.config.transition_seconds);
+                if in_transition && *expected != status {
+                    return Ok(());
+                }
+                self.transition = None;
+            }
+
+            self.data.status = Some(status);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,112 @@
+use axum::{extract::{Path, Query, State}, routing::get, Json, Router};
+use chrono::{DateTime, Duration, Utc};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::get_timezone, SafeAppState};
+
+use super::{CostPoint, HistoryPoint, Resolution};
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    // both RFC 3339, the last day when missing
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    #[serde(default)]
+    resolution: Resolution,
+}
+
+async fn get_switch_history(
+    State(state): State<SafeAppState>,
+    Path(id): Path<u32>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Vec<HistoryPoint>>, (StatusCode, String)> {
+    let lock = state.read().await;
+
+    if !lock.switches.contains_key(&id) {
+        return Err((StatusCode::BAD_REQUEST, "Could not find any switch with the given id".to_owned()));
+    }
+
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query.from.unwrap_or(to - Duration::days(1));
+    if from > to {
+        return Err((StatusCode::BAD_REQUEST, "from must come before to".to_owned()));
+    }
+
+    let tz = get_timezone(&lock.config.timezone_override);
+    let points = lock
+        .history
+        .get(&id)
+        .map(|x| x.query(from, to, query.resolution, tz))
+        .unwrap_or_default();
+
+    Ok(Json(points))
+}
+
+#[derive(Deserialize)]
+struct CostQuery {
+    // both RFC 3339, the last 30 days when missing
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    #[serde(default = "default_cost_period")]
+    period: Resolution,
+}
+
+fn default_cost_period() -> Resolution {
+    Resolution::Day
+}
+
+#[derive(Serialize)]
+struct CostResponse {
+    currency: String,
+    total_energy_kwh: f64,
+    total_cost: f64,
+    periods: Vec<CostPoint>,
+}
+
+async fn get_switch_cost(
+    State(state): State<SafeAppState>,
+    Path(id): Path<u32>,
+    Query(query): Query<CostQuery>,
+) -> Result<Json<CostResponse>, (StatusCode, String)> {
+    let lock = state.read().await;
+
+    if !lock.switches.contains_key(&id) {
+        return Err((StatusCode::BAD_REQUEST, "Could not find any switch with the given id".to_owned()));
+    }
+
+    let Some(tariff) = &lock.config.tariff else {
+        return Err((StatusCode::BAD_REQUEST, "No [tariff] section in config.toml".to_owned()));
+    };
+
+    if !matches!(query.period, Resolution::Day | Resolution::Week | Resolution::Month) {
+        return Err((StatusCode::BAD_REQUEST, "Period must be either day, week or month".to_owned()));
+    }
+
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query.from.unwrap_or(to - Duration::days(30));
+    if from > to {
+        return Err((StatusCode::BAD_REQUEST, "from must come before to".to_owned()));
+    }
+
+    let tz = get_timezone(&lock.config.timezone_override);
+    let periods = lock
+        .history
+        .get(&id)
+        .map(|x| x.cost(from, to, query.period, tz, tariff))
+        .unwrap_or_default();
+
+    Ok(Json(CostResponse {
+        currency: tariff.currency.clone(),
+        total_energy_kwh: periods.iter().map(|x| x.energy_kwh).sum(),
+        total_cost: periods.iter().map(|x| x.cost).sum(),
+        periods,
+    }))
+}
+
+pub fn add_history_routes(state: SafeAppState) -> Router {
+    Router::new()
+        .route("/api/switch/{id}/history", get(get_switch_history))
+        .route("/api/switch/{id}/cost", get(get_switch_cost))
+        .with_state(state)
+}
@@ -0,0 +1,462 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Datelike, Duration, DurationRound, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::TariffConfig, devices::Telemetry, storage::get_storage_path, timers::day_of_week, SafeAppState};
+
+pub mod http;
+
+// raw samples are kept for a day, hourly aggregates for a year
+const RAW_RETENTION_HOURS: i64 = 24;
+const HOURLY_RETENTION_DAYS: i64 = 365;
+const SAMPLE_INTERVAL_SECONDS: u64 = 60;
+// readings older than this belong to a device that stopped answering and are not recorded
+const MAX_SAMPLE_AGE_SECONDS: i64 = 2 * SAMPLE_INTERVAL_SECONDS as i64;
+// history files are only rewritten every few samples to spare SD cards
+const STORE_EVERY_SAMPLES: u32 = 15;
+
+/*
+* A reading or an aggregate over a period starting at `timestamp`.
+* `power_w` is the average power over the period, `energy_wh` the energy consumed during it.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryPoint {
+    pub timestamp: DateTime<Utc>,
+    pub power_w: Option<f64>,
+    pub energy_wh: Option<f64>,
+}
+
+impl HistoryPoint {
+    fn aggregate(timestamp: DateTime<Utc>, points: &[&HistoryPoint]) -> Self {
+        let powers: Vec<f64> = points.iter().filter_map(|x| x.power_w).collect();
+        let energies: Vec<f64> = points.iter().filter_map(|x| x.energy_wh).collect();
+
+        Self {
+            timestamp,
+            power_w: (!powers.is_empty()).then(|| powers.iter().sum::<f64>() / powers.len() as f64),
+            energy_wh: (!energies.is_empty()).then(|| energies.iter().sum()),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CostPoint {
+    pub timestamp: DateTime<Utc>,
+    pub energy_kwh: f64,
+    pub cost: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution {
+    Raw,
+    #[default]
+    Hour,
+    Day,
+    // weeks start on monday
+    Week,
+    Month,
+}
+
+impl Resolution {
+    // Start of the period `timestamp` falls in, in the given timezone.
+    fn period_start(&self, timestamp: DateTime<Utc>, tz: Tz) -> DateTime<Utc> {
+        let date = timestamp.with_timezone(&tz).date_naive();
+        let first_day = match self {
+            Resolution::Raw => return timestamp,
+            Resolution::Hour => return hour_start(timestamp),
+            Resolution::Day => date,
+            Resolution::Week => date - Duration::days(day_of_week(&date) as i64),
+            Resolution::Month => date.with_day(1).unwrap(),
+        };
+
+        tz.from_local_datetime(&first_day.and_hms_opt(0, 0, 0).unwrap())
+            .earliest()
+            .map(|x| x.with_timezone(&Utc))
+            .unwrap_or(timestamp)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SwitchHistory {
+    // last value of the device's energy counter, consumption is the difference between two readings
+    pub last_energy_counter_wh: Option<f64>,
+    #[serde(default)]
+    pub raw: Vec<HistoryPoint>,
+    #[serde(default)]
+    pub hourly: Vec<HistoryPoint>,
+}
+
+fn hour_start(timestamp: DateTime<Utc>) -> DateTime<Utc> {
+    timestamp.duration_trunc(Duration::hours(1)).unwrap_or(timestamp)
+}
+
+impl SwitchHistory {
+    /*
+    * Adds a reading. Once a reading from a new hour comes in, the samples of the previous one are folded into `hourly`.
+    */
+    pub fn record(&mut self, timestamp: DateTime<Utc>, power_w: Option<f64>, energy_counter_wh: Option<f64>) {
+        if self.raw.last().is_some_and(|x| x.timestamp >= timestamp) {
+            return;
+        }
+
+        let energy_wh = match (self.last_energy_counter_wh, energy_counter_wh) {
+            // the counter goes back to zero when the device restarts
+            (Some(last), Some(current)) if current < last => Some(current),
+            (Some(last), Some(current)) => Some(current - last),
+            _ => None,
+        };
+        if energy_counter_wh.is_some() {
+            self.last_energy_counter_wh = energy_counter_wh;
+        }
+
+        if let Some(last) = self.raw.last() {
+            let last_hour = hour_start(last.timestamp);
+            if last_hour < hour_start(timestamp) && self.hourly.last().is_none_or(|x| x.timestamp < last_hour) {
+                let points: Vec<&HistoryPoint> = self.raw.iter().filter(|x| hour_start(x.timestamp) == last_hour).collect();
+                self.hourly.push(HistoryPoint::aggregate(last_hour, &points));
+            }
+        }
+
+        self.raw.push(HistoryPoint { timestamp, power_w, energy_wh });
+
+        self.raw.retain(|x| x.timestamp > timestamp - Duration::hours(RAW_RETENTION_HOURS));
+        self.hourly.retain(|x| x.timestamp > timestamp - Duration::days(HOURLY_RETENTION_DAYS));
+    }
+
+    // Hourly aggregates, including the hour still in progress.
+    fn hours(&self) -> Vec<HistoryPoint> {
+        let mut out = self.hourly.clone();
+
+        if let Some(last) = self.raw.last() {
+            let current_hour = hour_start(last.timestamp);
+            if out.last().is_none_or(|x| x.timestamp < current_hour) {
+                let points: Vec<&HistoryPoint> = self.raw.iter().filter(|x| hour_start(x.timestamp) == current_hour).collect();
+                out.push(HistoryPoint::aggregate(current_hour, &points));
+            }
+        }
+
+        out
+    }
+
+    /*
+    * Points between `from` and `to`, days, weeks and months follow the configured timezone.
+    */
+    pub fn query(&self, from: DateTime<Utc>, to: DateTime<Utc>, resolution: Resolution, tz: Tz) -> Vec<HistoryPoint> {
+        let in_range = |x: &HistoryPoint| x.timestamp >= from && x.timestamp <= to;
+
+        if resolution == Resolution::Raw {
+            return self.raw.iter().filter(|x| in_range(x)).cloned().collect();
+        }
+
+        let hours: Vec<HistoryPoint> = self.hours().into_iter().filter(in_range).collect();
+
+        let mut buckets: BTreeMap<DateTime<Utc>, Vec<&HistoryPoint>> = BTreeMap::new();
+        for point in &hours {
+            buckets.entry(resolution.period_start(point.timestamp, tz)).or_default().push(point);
+        }
+
+        buckets
+            .into_iter()
+            .map(|(timestamp, points)| HistoryPoint::aggregate(timestamp, &points))
+            .collect()
+    }
+
+    /*
+    * Every hour is priced at the rate in force when it started, then hours are summed per period.
+    */
+    pub fn cost(&self, from: DateTime<Utc>, to: DateTime<Utc>, resolution: Resolution, tz: Tz, tariff: &TariffConfig) -> Vec<CostPoint> {
+        let mut buckets: BTreeMap<DateTime<Utc>, CostPoint> = BTreeMap::new();
+
+        for point in self.hours().iter().filter(|x| x.timestamp >= from && x.timestamp <= to) {
+            let energy_kwh = point.energy_wh.unwrap_or_default() / 1000.0;
+            let price = tariff.price_at(point.timestamp.with_timezone(&tz));
+            let timestamp = resolution.period_start(point.timestamp, tz);
+
+            let bucket = buckets.entry(timestamp).or_insert(CostPoint { timestamp, energy_kwh: 0.0, cost: 0.0 });
+            bucket.energy_kwh += energy_kwh;
+            bucket.cost += energy_kwh * price;
+        }
+
+        buckets.into_values().collect()
+    }
+}
+
+fn history_path() -> PathBuf {
+    get_storage_path().join("history")
+}
+
+pub fn parse_history_from_files() -> HashMap<u32, SwitchHistory> {
+    parse_history_from(&history_path())
+}
+
+fn parse_history_from(folder: &Path) -> HashMap<u32, SwitchHistory> {
+    let mut out = HashMap::new();
+
+    let Ok(entries) = std::fs::read_dir(folder) else {
+        log::info!("No history folder found.");
+        return out;
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(id) = path.file_stem().and_then(|x| x.to_str()).and_then(|x| x.parse::<u32>().ok()) else {
+            continue;
+        };
+
+        match std::fs::read_to_string(&path).map(|x| toml::from_str::<SwitchHistory>(&x)) {
+            Ok(Ok(history)) => { out.insert(id, history); },
+            Ok(Err(e)) => log::warn!("Could not parse {}: {}", path.display(), e),
+            Err(e) => log::warn!("Could not read {}: {}", path.display(), e),
+        }
+    }
+    log::info!("Loaded history of {} switches.", out.len());
+
+    out
+}
+
+pub fn store_history(history: &HashMap<u32, SwitchHistory>) {
+    store_history_to(&history_path(), history);
+}
+
+fn store_history_to(folder: &Path, history: &HashMap<u32, SwitchHistory>) {
+    if let Err(e) = std::fs::create_dir_all(folder) {
+        log::warn!("Could not create {}: {:?}", folder.display(), e);
+        return;
+    }
+
+    for (id, switch_history) in history {
+        let path = folder.join(format!("{}.toml", id));
+        let content = toml::to_string(switch_history).expect("Could not serialize switch history.");
+        if let Err(e) = std::fs::write(&path, content) {
+            log::warn!("Could not write to {}: {:?}", path.display(), e);
+        }
+    }
+}
+
+/*
+* Takes the telemetry gathered by the status polling once a minute and records it. Telemetry is read from the
+* switches' snapshots so a busy switch does not hold the others back, and the files are written from a copy
+* of the history, away from the app state lock.
+*/
+pub async fn history_task(state: SafeAppState) {
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(SAMPLE_INTERVAL_SECONDS));
+    let mut samples_since_store = 0;
+
+    loop {
+        interval.tick().await;
+
+        let readings: Vec<(u32, Telemetry)> = state
+            .read()
+            .await
+            .switches
+            .iter()
+            .filter_map(|(id, switch)| switch.telemetry().map(|x| (*id, x)))
+            .collect();
+
+        let now = Utc::now();
+        let mut lock = state.write().await;
+        for (id, telemetry) in readings {
+            if now - telemetry.timestamp > Duration::seconds(MAX_SAMPLE_AGE_SECONDS) {
+                continue;
+            }
+
+            lock.history
+                .entry(id)
+                .or_default()
+                .record(telemetry.timestamp, telemetry.power_w, telemetry.energy_wh);
+        }
+
+        samples_since_store += 1;
+        if samples_since_store >= STORE_EVERY_SAMPLES {
+            let history = lock.history.clone();
+            drop(lock);
+            tokio::task::spawn_blocking(move || store_history(&history));
+            samples_since_store = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AMSTERDAM: Tz = chrono_tz::Europe::Amsterdam;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    // One reading per hour from `from` to `to` included, the counter going up by 1 Wh every hour
+    fn hourly_readings(from: DateTime<Utc>, to: DateTime<Utc>) -> SwitchHistory {
+        let mut history = SwitchHistory::default();
+        let mut timestamp = from;
+        let mut counter = 0.0;
+        while timestamp <= to {
+            history.record(timestamp, Some(100.0), Some(counter));
+            timestamp += Duration::hours(1);
+            counter += 1.0;
+        }
+        history
+    }
+
+    fn flat_tariff(price_per_kwh: f64) -> TariffConfig {
+        TariffConfig { price_per_kwh, currency: "EUR".to_owned(), periods: vec![] }
+    }
+
+    #[test]
+    fn hours_are_folded_once_the_next_one_starts() {
+        let mut history = SwitchHistory::default();
+        history.record(utc("2026-10-19T10:00:00Z"), Some(10.0), Some(100.0));
+        history.record(utc("2026-10-19T10:30:00Z"), Some(20.0), Some(110.0));
+        assert!(history.hourly.is_empty());
+
+        history.record(utc("2026-10-19T11:05:00Z"), Some(30.0), Some(130.0));
+
+        assert_eq!(history.hourly.len(), 1);
+        assert_eq!(history.hourly[0].timestamp, utc("2026-10-19T10:00:00Z"));
+        assert_eq!(history.hourly[0].power_w, Some(15.0));
+        // the first reading only sets the counter
+        assert_eq!(history.hourly[0].energy_wh, Some(10.0));
+        assert_eq!(history.raw.len(), 3);
+        assert_eq!(history.raw[2].energy_wh, Some(20.0));
+
+        // the hour in progress is part of the queries
+        let hours = history.query(utc("2026-10-19T00:00:00Z"), utc("2026-10-20T00:00:00Z"), Resolution::Hour, AMSTERDAM);
+        assert_eq!(hours.len(), 2);
+        assert_eq!(hours[1].energy_wh, Some(20.0));
+    }
+
+    #[test]
+    fn readings_out_of_order_are_ignored() {
+        let mut history = SwitchHistory::default();
+        history.record(utc("2026-10-19T10:30:00Z"), Some(10.0), Some(100.0));
+        history.record(utc("2026-10-19T10:00:00Z"), Some(20.0), Some(50.0));
+
+        assert_eq!(history.raw.len(), 1);
+        assert_eq!(history.last_energy_counter_wh, Some(100.0));
+    }
+
+    #[test]
+    fn counter_resets_count_from_zero() {
+        let mut history = SwitchHistory::default();
+        history.record(utc("2026-10-19T10:00:00Z"), None, Some(100.0));
+        history.record(utc("2026-10-19T10:01:00Z"), None, Some(110.0));
+        // the device restarted
+        history.record(utc("2026-10-19T10:02:00Z"), None, Some(5.0));
+        // a reading without the counter keeps the last one
+        history.record(utc("2026-10-19T10:03:00Z"), None, None);
+        history.record(utc("2026-10-19T10:04:00Z"), None, Some(8.0));
+
+        let energies: Vec<Option<f64>> = history.raw.iter().map(|x| x.energy_wh).collect();
+        assert_eq!(energies, vec![None, Some(10.0), Some(5.0), None, Some(3.0)]);
+    }
+
+    #[test]
+    fn old_readings_are_dropped() {
+        let mut history = SwitchHistory::default();
+        history.record(utc("2025-10-19T10:00:00Z"), Some(10.0), None);
+        history.record(utc("2025-10-20T11:00:00Z"), Some(20.0), None);
+
+        // raw readings are kept for a day
+        assert_eq!(history.raw.len(), 1);
+        assert_eq!(history.hourly.len(), 1);
+
+        history.record(utc("2026-10-20T10:30:00Z"), Some(30.0), None);
+
+        // hourly aggregates for a year
+        assert_eq!(history.raw.len(), 1);
+        assert_eq!(history.hourly.len(), 1);
+        assert_eq!(history.hourly[0].timestamp, utc("2025-10-20T11:00:00Z"));
+    }
+
+    #[test]
+    fn days_follow_the_timezone_across_dst() {
+        // clocks go back in Amsterdam on 2026-10-25 at 03:00, that day lasts 25 hours
+        let history = hourly_readings(utc("2026-10-23T23:00:00Z"), utc("2026-10-26T00:00:00Z"));
+
+        let days = history.query(utc("2026-10-24T00:00:00Z"), utc("2026-10-26T00:00:00Z"), Resolution::Day, AMSTERDAM);
+
+        let timestamps: Vec<DateTime<Utc>> = days.iter().map(|x| x.timestamp).collect();
+        assert_eq!(timestamps, vec![
+            utc("2026-10-23T22:00:00Z"),
+            utc("2026-10-24T22:00:00Z"),
+            utc("2026-10-25T23:00:00Z"),
+        ]);
+        assert_eq!(days[1].energy_wh, Some(25.0));
+        assert_eq!(days[1].power_w, Some(100.0));
+        assert_eq!(days[2].energy_wh, Some(2.0));
+    }
+
+    #[test]
+    fn weeks_start_on_monday_and_months_on_the_first_in_the_timezone() {
+        let history = hourly_readings(utc("2026-10-25T22:00:00Z"), utc("2026-11-01T00:00:00Z"));
+        let (from, to) = (utc("2026-10-25T22:00:00Z"), utc("2026-11-01T00:00:00Z"));
+
+        // the readings start on sunday 23:00 in Amsterdam, the next week at midnight
+        let weeks = history.query(from, to, Resolution::Week, AMSTERDAM);
+        let timestamps: Vec<DateTime<Utc>> = weeks.iter().map(|x| x.timestamp).collect();
+        assert_eq!(timestamps, vec![utc("2026-10-18T22:00:00Z"), utc("2026-10-25T23:00:00Z")]);
+        assert_eq!(weeks[0].energy_wh, None);
+
+        // and end on november 1st 01:00 in Amsterdam, the next month starting at midnight
+        let months = history.query(from, to, Resolution::Month, AMSTERDAM);
+        let timestamps: Vec<DateTime<Utc>> = months.iter().map(|x| x.timestamp).collect();
+        assert_eq!(timestamps, vec![utc("2026-09-30T22:00:00Z"), utc("2026-10-31T23:00:00Z")]);
+        assert_eq!(months[1].energy_wh, Some(2.0));
+    }
+
+    #[test]
+    fn cost_sums_the_energy_of_every_hour() {
+        let mut history = SwitchHistory::default();
+        history.record(utc("2026-10-19T10:00:00Z"), None, Some(0.0));
+        history.record(utc("2026-10-19T10:30:00Z"), None, Some(500.0));
+        history.record(utc("2026-10-19T11:00:00Z"), None, Some(1500.0));
+        history.record(utc("2026-10-19T12:00:00Z"), None, Some(2500.0));
+
+        let cost = history.cost(utc("2026-10-19T00:00:00Z"), utc("2026-10-20T00:00:00Z"), Resolution::Day, AMSTERDAM, &flat_tariff(0.30));
+
+        assert_eq!(cost.len(), 1);
+        assert_eq!(cost[0].timestamp, utc("2026-10-18T22:00:00Z"));
+        assert!((cost[0].energy_kwh - 2.5).abs() < 1e-9);
+        assert!((cost[0].cost - 0.75).abs() < 1e-9);
+    }
+
+    #[test]
+    fn cost_prices_every_hour_at_the_rate_it_started_at() {
+        // cheaper from 23:00 to 07:00 in Amsterdam on weeknights
+        let tariff = TariffConfig {
+            periods: vec![crate::config::TariffPeriod { price_per_kwh: 0.10, start_time: 23 * 60, end_time: 7 * 60, days: vec![0, 1, 2, 3, 4] }],
+            ..flat_tariff(0.30)
+        };
+        // monday 2026-10-19 from 20:00 to tuesday 10:00 in Amsterdam, 1 Wh every hour
+        let history = hourly_readings(utc("2026-10-19T18:00:00Z"), utc("2026-10-20T08:00:00Z"));
+
+        let cost = history.cost(utc("2026-10-19T00:00:00Z"), utc("2026-10-21T00:00:00Z"), Resolution::Day, AMSTERDAM, &tariff);
+
+        assert_eq!(cost.len(), 2);
+        // monday: 20:00, 21:00 and 22:00 at the day rate, 23:00 at the night rate, the first reading has no energy
+        assert!((cost[0].energy_kwh - 0.003).abs() < 1e-9);
+        assert!((cost[0].cost - (0.002 * 0.30 + 0.001 * 0.10)).abs() < 1e-9);
+        // tuesday: midnight to 06:00 at the night rate, 07:00 to 10:00 at the day rate
+        assert!((cost[1].energy_kwh - 0.011).abs() < 1e-9);
+        assert!((cost[1].cost - (0.007 * 0.10 + 0.004 * 0.30)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn history_survives_a_restart() {
+        let folder = std::env::temp_dir().join(format!("rsm-history-{}", uuid::Uuid::new_v4()));
+        let history = HashMap::from([(3, hourly_readings(utc("2026-10-19T10:00:00Z"), utc("2026-10-19T12:00:00Z")))]);
+
+        store_history_to(&folder, &history);
+        let parsed = parse_history_from(&folder);
+        std::fs::remove_dir_all(&folder).unwrap();
+
+        assert_eq!(parsed[&3].raw.len(), 3);
+        assert_eq!(parsed[&3].hourly.len(), 2);
+        assert_eq!(parsed[&3].last_energy_counter_wh, Some(2.0));
+    }
+}
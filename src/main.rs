@@ -1,11 +1,15 @@
+use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
 use std::sync::Arc;
 
 use axum::response::{Html, IntoResponse, Response};
 use axum::{routing::get, Router};
-use devices::{parse_switches_from_file, Switch};
+use devices::mqtt::MqttBroker;
+use devices::{parse_switches_from_file, SafeSwitch};
+use history::{parse_history_from_files, SwitchHistory};
 use http::{header, StatusCode, Uri};
 use rust_embed::Embed;
+use timers::countdown::{parse_countdowns_from_file, Countdown};
 use timers::{parse_timers_from_file, Timer};
 use tokio::sync::RwLock;
 use users::parse_users_from_file;
@@ -14,6 +18,7 @@ use users::User;
 pub mod auth;
 pub mod config;
 pub mod devices;
+pub mod history;
 pub mod timers;
 pub mod users;
 pub mod storage;
@@ -22,20 +27,27 @@ pub mod storage;
 pub struct AppState {
     pub config: config::Config,
     pub users: Vec<User>,
-    pub switches: Vec<Box<dyn Switch>>,
+    pub switches: BTreeMap<u32, SafeSwitch>,
     pub timers: Vec<Timer>,
+    pub countdowns: Vec<Countdown>,
+    pub mqtt: Option<Arc<MqttBroker>>,
+    pub history: HashMap<u32, SwitchHistory>,
 }
 
 impl AppState {
     pub fn new() -> Self {
         let config = config::Config::new();
         let users = parse_users_from_file(&config);
+        let mqtt = config.mqtt.as_ref().map(|x| Arc::new(MqttBroker::new(x)));
 
         Self {
             config,
             users,
-            switches: parse_switches_from_file(),
+            switches: parse_switches_from_file(&mqtt),
             timers: parse_timers_from_file(),
+            countdowns: parse_countdowns_from_file(),
+            mqtt,
+            history: parse_history_from_files(),
         }
     }
 }
@@ -60,6 +72,14 @@ async fn main() {
 
         let devices_state = state.clone();
         tokio::spawn(async move { devices::devices_status_task(devices_state).await });
+
+        let history_state = state.clone();
+        tokio::spawn(async move { history::history_task(history_state).await });
+
+        if let Some(broker) = state.read().await.mqtt.clone() {
+            let mqtt_state = state.clone();
+            tokio::spawn(async move { devices::mqtt::mqtt_task(broker, mqtt_state).await });
+        }
     }
 
     let cors = tower_http::cors::CorsLayer::new()
@@ -75,6 +95,7 @@ async fn main() {
         .merge(auth::add_auth_routes(state.clone()))
         .merge(devices::http::add_devices_routes(state.clone()))
         .merge(timers::http::add_timers_routes(state.clone()))
+        .merge(history::http::add_history_routes(state.clone()))
         .layer(cors)
         .layer(axum::middleware::from_fn_with_state(
             state.clone(),
@@ -0,0 +1,82 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::get_storage_path;
+
+/*
+* A switch that has to be set to `then_on` once `deadline` passes, e.g. a heater turned on for 30 minutes.
+* While it runs the regular timers of that switch are not applied.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Countdown {
+    pub switch_id: u32,
+    pub then_on: bool,
+    pub deadline: DateTime<Utc>,
+}
+
+// What the API shows of a running countdown
+#[derive(Serialize, Debug, Clone)]
+pub struct CountdownStatus {
+    pub then_on: bool,
+    pub deadline: DateTime<Utc>,
+    pub remaining_seconds: i64,
+}
+
+impl Countdown {
+    pub fn status(&self) -> CountdownStatus {
+        CountdownStatus {
+            then_on: self.then_on,
+            deadline: self.deadline,
+            remaining_seconds: (self.deadline - Utc::now()).num_seconds().max(0),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.deadline <= Utc::now()
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct CountdownsArray {
+    countdowns: Vec<Countdown>,
+}
+
+pub fn parse_countdowns_from_file() -> Vec<Countdown> {
+    let countdowns_toml = get_storage_path().join("countdowns.toml");
+
+    if !std::path::Path::exists(&countdowns_toml) {
+        return Vec::new();
+    }
+
+    log::info!("Parsing countdowns.toml");
+    let countdowns_str = std::fs::read_to_string(countdowns_toml)
+        .expect("Unable to parse countdowns.toml. Check permissions.");
+    let out = toml::from_str::<CountdownsArray>(&countdowns_str)
+        .expect("Unable to parse countdowns.toml content")
+        .countdowns;
+    log::info!("Parsed {} countdowns.", out.len());
+
+    out
+}
+
+pub fn store_countdowns(countdowns: &[Countdown]) {
+    let countdowns_toml = get_storage_path().join("countdowns.toml");
+
+    std::fs::write(countdowns_toml, toml::to_string(&CountdownsArray { countdowns: countdowns.to_vec() }).expect("Could not serialize countdowns array.")).expect("Could not write to countdowns.toml, check permissions.");
+}
+
+// A switch has at most one countdown, a new one replaces the previous.
+pub fn set_countdown(countdowns: &mut Vec<Countdown>, countdown: Countdown) {
+    countdowns.retain(|x| x.switch_id != countdown.switch_id);
+    countdowns.push(countdown);
+    store_countdowns(countdowns);
+}
+
+pub fn clear_countdown(countdowns: &mut Vec<Countdown>, switch_id: u32) {
+    let len = countdowns.len();
+    countdowns.retain(|x| x.switch_id != switch_id);
+
+    if countdowns.len() != len {
+        store_countdowns(countdowns);
+    }
+}
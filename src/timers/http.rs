@@ -1,10 +1,12 @@
-use axum::{extract::{Path, State}, routing::{get, post}, Json, Router};
+use axum::{extract::{Path, State}, routing::{get, post, put}, Json, Router};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use http::StatusCode;
 use serde::Serialize;
 
-use crate::SafeAppState;
+use crate::{config::get_timezone, AppState, SafeAppState};
 
-use super::{store_timers, Timer};
+use super::{store_timers, sun::sun_times, Timer};
 
 pub async fn get_device_timers(
     State(state): State<SafeAppState>,
@@ -22,14 +24,41 @@ pub async fn get_device_timers(
 }
 
 #[derive(Serialize)]
-struct AddTimerResponse { success: bool }
+struct TimerResponse { success: bool }
+
+// All the reasons a timer is rejected are reported at once, separated by `; `.
+fn validate_timer(state: &AppState, timer: &Timer) -> Result<(), (StatusCode, String)> {
+    let mut errors = timer.validate();
+
+    if !state.switches.contains_key(&timer.switch_id) {
+        errors.push(format!("there is no switch with id {}", timer.switch_id));
+    }
+    if timer.uses_sun() && state.config.location.is_none() {
+        errors.push("startSun and endSun need a [location] in config.toml".to_owned());
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err((StatusCode::BAD_REQUEST, errors.join("; ")))
+    }
+}
+
+fn find_timer(timers: &mut [Timer], id: u32) -> Result<&mut Timer, (StatusCode, String)> {
+    timers
+        .iter_mut()
+        .find(|x| x.id == id)
+        .ok_or((StatusCode::BAD_REQUEST, "Could not find any timer with the given id".to_owned()))
+}
 
 async fn add_timer(
     State(state): State<SafeAppState>,
     Json(mut timer): Json<Timer> 
-) -> Result<Json<AddTimerResponse>, (StatusCode, String)>
+) -> Result<Json<TimerResponse>, (StatusCode, String)>
 {
     let mut lock = state.write().await;
+
+    validate_timer(&lock, &timer)?;
     
     let new_id = make_timer_id(&lock.timers);
 
@@ -39,23 +68,108 @@ async fn add_timer(
 
     store_timers(&lock.timers);
 
-    Ok(Json(AddTimerResponse { success: true }))
+    Ok(Json(TimerResponse { success: true }))
+}
+
+async fn update_timer(
+    State(state): State<SafeAppState>,
+    Path(id): Path<u32>,
+    Json(mut timer): Json<Timer>,
+) -> Result<Json<TimerResponse>, (StatusCode, String)> {
+    let mut lock = state.write().await;
+
+    validate_timer(&lock, &timer)?;
+
+    timer.id = id;
+    *find_timer(&mut lock.timers, id)? = timer;
+
+    store_timers(&lock.timers);
+
+    Ok(Json(TimerResponse { success: true }))
+}
+
+async fn delete_timer(
+    State(state): State<SafeAppState>,
+    Path(id): Path<u32>,
+) -> Result<Json<TimerResponse>, (StatusCode, String)> {
+    let mut lock = state.write().await;
+
+    find_timer(&mut lock.timers, id)?;
+    lock.timers.retain(|x| x.id != id);
+
+    store_timers(&lock.timers);
+
+    Ok(Json(TimerResponse { success: true }))
+}
+
+async fn enable_timer(
+    State(state): State<SafeAppState>,
+    Path(id): Path<u32>,
+) -> Result<Json<TimerResponse>, (StatusCode, String)> {
+    let mut lock = state.write().await;
+
+    find_timer(&mut lock.timers, id)?.activate();
+
+    store_timers(&lock.timers);
+
+    Ok(Json(TimerResponse { success: true }))
 }
 
-fn make_timer_id(timers: &Vec<Timer>) -> u32 {
+async fn disable_timer(
+    State(state): State<SafeAppState>,
+    Path(id): Path<u32>,
+) -> Result<Json<TimerResponse>, (StatusCode, String)> {
+    let mut lock = state.write().await;
+
+    find_timer(&mut lock.timers, id)?.deactivate();
+
+    store_timers(&lock.timers);
+
+    Ok(Json(TimerResponse { success: true }))
+}
+
+#[derive(Serialize)]
+struct SunResponse {
+    sunrise: Option<DateTime<Tz>>,
+    sunset: Option<DateTime<Tz>>,
+}
+
+// Today's sunrise and sunset at the configured location, both missing on polar days and nights.
+async fn get_sun(
+    State(state): State<SafeAppState>,
+) -> Result<Json<SunResponse>, (StatusCode, String)> {
+    let lock = state.read().await;
+    let Some(location) = &lock.config.location else {
+        return Err((StatusCode::BAD_REQUEST, "No location is configured".to_owned()));
+    };
+
+    let timezone = get_timezone(&lock.config.timezone_override);
+    let today = Utc::now().with_timezone(&timezone).date_naive();
+    let times = sun_times(today, location.latitude, location.longitude);
+
+    Ok(Json(SunResponse {
+        sunrise: times.map(|(sunrise, _)| sunrise.with_timezone(&timezone)),
+        sunset: times.map(|(_, sunset)| sunset.with_timezone(&timezone)),
+    }))
+}
+
+fn make_timer_id(timers: &[Timer]) -> u32 {
     let mut id = 0;
 
     for timer in timers {
         id = timer.id.max(id);
     }
 
-    return id + 1;
+    id + 1
 }
 
 pub fn add_timers_routes(state: SafeAppState) -> Router {
     Router::new()
         .route("/api/timers/{id}", get(get_device_timers))
         .route("/api/timer", post(add_timer))
+        .route("/api/timer/{id}", put(update_timer).delete(delete_timer))
+        .route("/api/timer/{id}/enable", post(enable_timer))
+        .route("/api/timer/{id}/disable", post(disable_timer))
+        .route("/api/sun", get(get_sun))
         .with_state(state)
 }
-
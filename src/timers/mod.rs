@@ -1,64 +1,182 @@
-use chrono::{DateTime, Datelike, Local, Utc};
+use std::path::Path;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, NaiveDate, Timelike, Utc};
 use serde::{Deserialize, Serialize};
 use chrono_tz::Tz;
+use cron::Schedule;
+use countdown::{store_countdowns, Countdown};
+use sun::{SunEvent, SunTrigger};
 
-use crate::{devices::DeviceStatus, storage::get_storage_path, SafeAppState};
+use crate::{config::{get_timezone, LocationConfig}, devices::{set_switch_state, DeviceStatus, SafeSwitch}, storage::get_storage_path, SafeAppState};
 
+pub mod countdown;
 pub mod http;
+pub mod sun;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Timer {
     pub id: u32,      
     pub switch_id: u32,    
+    #[serde(default)]
     pub start_time: u32, // Start time in minutes after midnight
-    pub end_time: u32,   // End time in minutes after midnight
-    pub days: Vec<u8>,   // Array of days (0=Sunday, 6=Saturday)
+    #[serde(default)]
+    pub end_time: u32,   // End time in minutes after midnight, lower than start_time for overnight windows
+    #[serde(default)]
+    pub days: Vec<u8>,   // Array of days the window starts on (0=Monday, 6=Sunday, see `day_of_week`)
     pub is_active: bool,  
     pub one_off: bool,
+    // Cron expressions (sec min hour day-of-month month day-of-week [year]) replacing the window above when set,
+    // e.g. `0 0 */2 * * *` / `0 15 */2 * * *` for 15 minutes every 2 hours
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_cron: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub off_cron: Option<String>,
+    // Replace start_time/end_time with a time relative to sunrise/sunset, e.g. 30 minutes after sunset until 23:00.
+    // Needs a `[location]` in config.toml
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_sun: Option<SunTrigger>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_sun: Option<SunTrigger>,
+}
+
+/*
+* The one day numbering used everywhere (timers, tariffs, the UI): 0=Monday, 1=Tuesday, ..., 6=Sunday.
+*/
+pub fn day_of_week(date: &impl Datelike) -> u8 {
+    date.weekday().num_days_from_monday() as u8
 }
 
 impl Timer {
     fn now(timezone: &Option<String>) -> DateTime<Tz> {
-        match timezone {
-            Some(tz_name) => {
-                let tz: Tz = tz_name
-                    .parse()
-                    .expect("Invalid timezone name. Use a valid IANA timezone, e.g., 'Europe/Rome'.");
-                
-                let now_utc: DateTime<Utc> = Utc::now();
-                now_utc.with_timezone(&tz)
-            }
-            None => {
-                let local_time = Local::now();
+        Utc::now().with_timezone(&get_timezone(timezone))
+    }
+    
+    pub fn should_be_on(&self, timezone_override: &Option<String>, location: Option<&LocationConfig>) -> bool {
+        self.should_be_on_at(Self::now(timezone_override), location)
+    }
 
-                let tz_name = iana_time_zone::get_timezone()
-                    .expect("Could not determine timezone.");
+    /*
+    * Overnight windows (see `is_overnight`) run into the next day, the part after midnight belongs to the day before,
+    * which is the one `days` is checked against.
+    * Sun triggers are resolved on the day each end falls on, a day where they can not be resolved is skipped, and so
+    * is a day where the sun moves the start of a window past its end (e.g. 06:00 until sunrise in summer).
+    */
+    pub fn should_be_on_at(&self, now: DateTime<Tz>, location: Option<&LocationConfig>) -> bool {
+        if self.is_cron() {
+            return self.cron_should_be_on_at(now);
+        }
 
-                let tz: Tz = tz_name
-                .parse()
-                .expect("Invalid timezone name.");
+        let minutes_since_midnight = now.hour() * 60 + now.minute();
+        let timezone = now.timezone();
+        let today = now.date_naive();
+        let start_on = |date| resolve_time(self.start_time, &self.start_sun, date, &timezone, location);
+        let end_on = |date| resolve_time(self.end_time, &self.end_sun, date, &timezone, location);
 
-                // Convrt the local time to UTC
-                local_time.with_timezone(&tz)
-            }
+        if !self.is_overnight() {
+            return self.days.contains(&day_of_week(&today))
+                && matches!(
+                    (start_on(today), end_on(today)),
+                    (Some(start), Some(end)) if start <= minutes_since_midnight && minutes_since_midnight <= end
+                );
         }
+
+        let started_today = self.days.contains(&day_of_week(&today))
+            && start_on(today).is_some_and(|start| minutes_since_midnight >= start);
+
+        let started_yesterday = today.pred_opt().is_some_and(|yesterday| {
+            self.days.contains(&day_of_week(&yesterday))
+                && start_on(yesterday).is_some()
+                && end_on(today).is_some_and(|end| minutes_since_midnight <= end)
+        });
+
+        started_today || started_yesterday
     }
-    
-    pub fn should_be_on(&self, timezone_override: &Option<String>) -> bool {
-        let minutes_since_midnight = Timer::minutes_since_midnight(timezone_override);
-        let now = Self::now(timezone_override);
-        let matches_day = self.days.contains(&(now.weekday().num_days_from_monday() as u8));
-        matches_day && minutes_since_midnight >= self.start_time.into() && minutes_since_midnight <= self.end_time.into()
+
+    /*
+    * Whether the window runs from an evening into the next morning, decided from the configuration rather than from
+    * the times of the day, as sun triggers can move past the other end: a fixed end lower than a fixed start, or
+    * an evening start (sunset, a fixed time from noon on) with a morning end (sunrise, a fixed time before noon).
+    */
+    fn is_overnight(&self) -> bool {
+        let is_evening = |fixed: u32, trigger: &Option<SunTrigger>| match trigger {
+            Some(trigger) => trigger.event == SunEvent::Sunset,
+            None => fixed >= MINUTES_IN_A_DAY / 2,
+        };
+
+        match (&self.start_sun, &self.end_sun) {
+            (None, None) => self.start_time > self.end_time,
+            _ => is_evening(self.start_time, &self.start_sun) && !is_evening(self.end_time, &self.end_sun),
+        }
     }
 
-    fn minutes_since_midnight(timezone_override: &Option<String>) -> u32 {
-        let now = Timer::now(timezone_override);
+    pub fn uses_sun(&self) -> bool {
+        self.start_sun.is_some() || self.end_sun.is_some()
+    }
 
-        let tomorrow_midnight = now.date_naive().and_hms_opt(0, 0, 1).unwrap();
-    
-        let duration = now.naive_local().signed_duration_since(tomorrow_midnight).to_std().unwrap();
-        (duration.as_secs() / 60) as _
+    pub fn is_cron(&self) -> bool {
+        self.on_cron.is_some() || self.off_cron.is_some()
+    }
+
+    /*
+    * The most recent of the on and off events decides, evaluated in the configured timezone.
+    * An event due during the current second counts as passed.
+    */
+    fn cron_should_be_on_at(&self, now: DateTime<Tz>) -> bool {
+        let (Some(Ok(on)), Some(Ok(off))) = (
+            self.on_cron.as_deref().map(Schedule::from_str),
+            self.off_cron.as_deref().map(Schedule::from_str),
+        ) else {
+            return false;
+        };
+
+        // looking back only gives the events strictly before the given time
+        let next_second = now.with_nanosecond(0).unwrap_or(now) + chrono::Duration::seconds(1);
+        match (on.after(&next_second).next_back(), off.after(&next_second).next_back()) {
+            (Some(last_on), Some(last_off)) => last_on > last_off,
+            (Some(_), None) => true,
+            _ => false,
+        }
+    }
+
+    // Reasons why the timer can not be used, empty when it is fine. The switch it refers to is checked by the caller.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.is_cron() {
+            for (name, expression) in [("onCron", &self.on_cron), ("offCron", &self.off_cron)] {
+                match expression.as_deref().map(Schedule::from_str) {
+                    Some(Ok(_)) => {},
+                    Some(Err(e)) => errors.push(format!("{} is not a valid cron expression: {}", name, e)),
+                    None => errors.push(format!("{} is required along with {}", name, if name == "onCron" { "offCron" } else { "onCron" })),
+                }
+            }
+            if self.uses_sun() {
+                errors.push("startSun and endSun can not be combined with cron expressions".to_owned());
+            }
+            return errors;
+        }
+
+        if self.start_time >= MINUTES_IN_A_DAY {
+            errors.push(format!("startTime must be between 0 and {}", MINUTES_IN_A_DAY - 1));
+        }
+        if self.end_time >= MINUTES_IN_A_DAY {
+            errors.push(format!("endTime must be between 0 and {}", MINUTES_IN_A_DAY - 1));
+        }
+        for (name, trigger) in [("startSun", &self.start_sun), ("endSun", &self.end_sun)] {
+            if trigger.as_ref().is_some_and(|x| x.offset_minutes.unsigned_abs() > MINUTES_IN_A_DAY / 2) {
+                errors.push(format!("{}.offsetMinutes must be between -{} and {}", name, MINUTES_IN_A_DAY / 2, MINUTES_IN_A_DAY / 2));
+            }
+        }
+        if self.days.is_empty() {
+            errors.push("days must contain at least one day".to_owned());
+        }
+        if self.days.iter().any(|x| *x > 6) {
+            errors.push("days must be between 0 (Monday) and 6 (Sunday)".to_owned());
+        }
+
+        errors
     }
 
     pub fn activate(&mut self) {
@@ -70,25 +188,72 @@ impl Timer {
     }
 }
 
+// The fixed time, or the one of the sun trigger replacing it, in minutes after midnight of `date`.
+fn resolve_time(fixed: u32, trigger: &Option<SunTrigger>, date: NaiveDate, timezone: &Tz, location: Option<&LocationConfig>) -> Option<u32> {
+    match trigger {
+        Some(trigger) => trigger.minutes_on(date, timezone, location?),
+        None => Some(fixed),
+    }
+}
+
+/*
+* Version of the timers.toml layout, bumped whenever the meaning of a field changes.
+* 1: `days` are explicitly 0=Monday based. Files without a version were documented as 0=Sunday, but the UI
+*    has always written (and the timers task read) monday based days, so they are only stamped, not shifted.
+*/
+const TIMERS_SCHEMA_VERSION: u32 = 1;
+pub const MINUTES_IN_A_DAY: u32 = 24 * 60;
+
 #[derive(Deserialize, Serialize)]
 struct TimersArray {
+    #[serde(default)]
+    version: u32,
     timers: Vec<Timer>,
 }
 
+// Brings timers written with an older schema up to TIMERS_SCHEMA_VERSION.
+fn migrate_timers(version: u32, timers: Vec<Timer>) -> Vec<Timer> {
+    if version > TIMERS_SCHEMA_VERSION {
+        log::warn!("timers.toml has schema version {}, newer than the supported {}.", version, TIMERS_SCHEMA_VERSION);
+    }
+
+    timers
+        .into_iter()
+        .map(|mut timer| {
+            if timer.is_cron() && !timer.validate().is_empty() {
+                log::warn!("Timer {} will never switch: {}", timer.id, timer.validate().join("; "));
+            }
+            if timer.days.iter().any(|x| *x > 6) {
+                log::warn!("Timer {} has days out of the 0 (Monday) - 6 (Sunday) range, ignoring them.", timer.id);
+                timer.days.retain(|x| *x <= 6);
+            }
+            timer
+        })
+        .collect()
+}
+
 pub fn parse_timers_from_file() -> Vec<Timer> {
-    let timers_toml = get_storage_path().join("timers.toml");
+    parse_timers_from(&get_storage_path().join("timers.toml"))
+}
+
+fn parse_timers_from(timers_toml: &Path) -> Vec<Timer> {
     log::info!("Looking for {}", timers_toml.display());
 
     let mut out = Vec::new();
 
-    if std::path::Path::exists(&timers_toml) {
+    if timers_toml.exists() {
         log::info!("Parsing timers.toml");
         let switches_str = std::fs::read_to_string(timers_toml)
             .expect("Unable to parse timers.toml. Check permissions.");
-        out = toml::from_str::<TimersArray>(&switches_str)
-            .expect("Unable to paese timers.toml content")
-            .timers;
+        let timers_array = toml::from_str::<TimersArray>(&switches_str)
+            .expect("Unable to paese timers.toml content");
+        out = migrate_timers(timers_array.version, timers_array.timers);
         log::info!("Parsed {} timers.", out.len());
+
+        if timers_array.version < TIMERS_SCHEMA_VERSION {
+            log::info!("Migrating timers.toml from schema version {} to {}.", timers_array.version, TIMERS_SCHEMA_VERSION);
+            store_timers_to(timers_toml, &out);
+        }
     } else {
         log::info!("No timers.toml found.");
     }
@@ -96,56 +261,389 @@ pub fn parse_timers_from_file() -> Vec<Timer> {
     out
 }
 
-pub fn store_timers(timers: &Vec<Timer>) {
-    let timers_toml = get_storage_path().join("timers.toml");
+pub fn store_timers(timers: &[Timer]) {
+    store_timers_to(&get_storage_path().join("timers.toml"), timers);
+}
+
+fn store_timers_to(timers_toml: &Path, timers: &[Timer]) {
     log::info!("Storing timers into {}", timers_toml.display());
 
-    std::fs::write(timers_toml, toml::to_string(&TimersArray { timers: timers.clone() }).expect("Could not serialize timers array.")).expect("Could not write to timers.toml, check permissions.");
+    std::fs::write(timers_toml, toml::to_string(&TimersArray { version: TIMERS_SCHEMA_VERSION, timers: timers.to_vec() }).expect("Could not serialize timers array.")).expect("Could not write to timers.toml, check permissions.");
 }
 
 
-pub async fn timers_task(state: SafeAppState) {
+#[derive(Default)]
+struct SwitchTimersOutcome {
+    // one-off timers that are done
+    finished_timers: Vec<u32>,
+    countdown_done: bool,
+}
+
+// First retry of an expired countdown that could not be applied, doubled for every further failure
+const COUNTDOWN_RETRY_SECONDS: u64 = 1;
+
+/*
+* Applies the timers of a single switch. A running countdown takes precedence over them, once it expires
+* the switch is set to the countdown's final state. If that fails it is retried with the same backoff as polling,
+* the timers stay on hold until the countdown could be applied.
+* Decisions are taken on the switch's snapshot, it is only locked when a command has to be sent, so a switch
+* that is busy with its device (e.g. a poll timing out) is not waited on for nothing.
+*/
+async fn apply_switch_timers(
+    switch: &SafeSwitch,
+    timers: &[Timer],
+    countdown: Option<&Countdown>,
+    timezone_override: &Option<String>,
+    location: Option<&LocationConfig>,
+) -> SwitchTimersOutcome {
+    let switch_data = switch.snapshot();
+    let mut outcome = SwitchTimersOutcome::default();
+
+    if let Some(countdown) = countdown {
+        if countdown.is_expired() && switch_data.availability.should_retry(COUNTDOWN_RETRY_SECONDS) {
+            log::info!("Turning {} switch {} because its countdown expired", if countdown.then_on { "on" } else { "off" }, switch_data.alias);
+            match set_switch_state(switch.lock().await.as_mut(), countdown.then_on).await {
+                Ok(()) => outcome.countdown_done = true,
+                Err(e) => log::warn!("Could not apply switch {}'s countdown: {}", switch_data.alias, e),
+            }
+        }
+        return outcome;
+    }
+
+    for timer in timers {
+        if !timer.is_active || timer.switch_id != switch_data.id {
+            continue;
+        }
+
+        let should_be_on = timer.should_be_on(timezone_override, location);
+        // Switches without any status feedback (a Command without `status_command`, an Http without `status`,
+        // an Mqtt without `state_topic`) have never reported anything, they are taken as off until commanded.
+        let current_switch_status = switch.snapshot().status.unwrap_or(DeviceStatus::Off);
+
+        if should_be_on && current_switch_status == DeviceStatus::Off {
+            log::info!("Turning on switch {} because of timer {}", switch_data.alias, timer.id);
+            if let Err(e) = set_switch_state(switch.lock().await.as_mut(), true).await {
+                log::warn!("Could not turn on switch {} for timer {}: {}", switch_data.alias, timer.id, e);
+                continue;
+            }
+        } else if !should_be_on && current_switch_status == DeviceStatus::On {
+            log::info!("Turning off switch {} because of timer {}", switch_data.alias, timer.id);
+            if let Err(e) = set_switch_state(switch.lock().await.as_mut(), false).await {
+                log::warn!("Could not turn off switch {} for timer {}: {}", switch_data.alias, timer.id, e);
+                continue;
+            }
+
+            if timer.one_off {
+                outcome.finished_timers.push(timer.id);
+            }
+        }
+    }
+
+    outcome
+}
+
+/*
+* The timers of a switch, checked every second. The app state is only locked to take a copy of the switch's
+* timers and countdown and, when a one-off timer or the countdown is done, to store the change.
+*/
+async fn switch_timers_task(state: SafeAppState, id: u32, switch: SafeSwitch) {
     let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(1));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
     loop {
-        {
+        interval.tick().await;
+
+        let (timers, countdown, timezone_override, location) = {
+            let lock = state.read().await;
+            let timers: Vec<Timer> = lock.timers.iter().filter(|x| x.switch_id == id).cloned().collect();
+            let countdown = lock.countdowns.iter().find(|x| x.switch_id == id).cloned();
+            (timers, countdown, lock.config.timezone_override.clone(), lock.config.location.clone())
+        };
+
+        let outcome = apply_switch_timers(&switch, &timers, countdown.as_ref(), &timezone_override, location.as_ref()).await;
+
+        if !outcome.finished_timers.is_empty() {
             let mut lock = state.write().await;
-            let state = &mut *lock;
-            let timers = &mut state.timers;
-            let switches = &mut state.switches;
-
-            let mut any_timer_changed = false;
-
-            for switch in &mut *switches {
-                let switch_data = switch.get_device_data().clone();
-                for timer in &mut *timers {
-                    if !timer.is_active || timer.switch_id != switch_data.id {
-                        continue;
-                    }
-
-                    let should_be_on = timer.should_be_on(&state.config.timezone_override);
-                    let current_switch_status = switch_data.status.as_ref().unwrap_or(&DeviceStatus::Unknown);
-
-                    if should_be_on && current_switch_status == &DeviceStatus::Off {
-                        log::info!("Turning on switch {} because of timer {}", switch_data.alias, timer.id);
-                        switch.turn_on().await;
-                    } else if !should_be_on && current_switch_status == &DeviceStatus::On {
-                        log::info!("Turning off switch {} because of timer {}", switch_data.alias, timer.id);
-                        switch.turn_off().await;
-
-                        if timer.one_off {
-                            timer.deactivate();
-                            any_timer_changed = true;
-                        }
-                    }
-                }
+            for timer in lock.timers.iter_mut().filter(|x| outcome.finished_timers.contains(&x.id)) {
+                timer.deactivate();
             }
+            store_timers(&lock.timers);
+        }
+
+        if let Some(applied) = countdown.filter(|_| outcome.countdown_done) {
+            let mut lock = state.write().await;
+            // only the countdown that was applied, a new one may have been set in the meantime
+            lock.countdowns.retain(|x| x.switch_id != applied.switch_id || x.deadline != applied.deadline);
+            store_countdowns(&lock.countdowns);
+        }
+    }
+}
+
+/*
+* Every switch runs its timers on its own, so a slow device only delays its own timers.
+*/
+pub async fn timers_task(state: SafeAppState) {
+    let switches: Vec<(u32, SafeSwitch)> = state.read().await.switches.iter().map(|(id, x)| (*id, x.clone())).collect();
+
+    futures::future::join_all(
+        switches
+            .into_iter()
+            .map(|(id, switch)| switch_timers_task(state.clone(), id, switch))
+    )
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::devices::command::{CommandConfig, CommandSwitch};
+    use crate::devices::{test_utils::device_data, virtual_switch::{VirtualConfig, VirtualSwitch}, Device, SwitchHandle};
+
+    fn whole_day_timer(days: Vec<u8>) -> Timer {
+        Timer { switch_id: 1, start_time: 0, end_time: MINUTES_IN_A_DAY - 1, days, is_active: true, ..Default::default() }
+    }
 
-            if any_timer_changed {
-                store_timers(&state.timers);
+    // noon of every day from Monday 2026-10-19 to Sunday 2026-10-25
+    fn week() -> Vec<DateTime<Tz>> {
+        let timezone: Tz = "Europe/Amsterdam".parse().unwrap();
+        (19..=25).map(|day| timezone.with_ymd_and_hms(2026, 10, day, 12, 0, 0).unwrap()).collect()
+    }
+
+    #[test]
+    fn days_are_monday_based() {
+        for (n, date) in week().iter().enumerate() {
+            assert_eq!(day_of_week(date) as usize, n);
+
+            let timer = whole_day_timer(vec![n as u8]);
+            for (m, other) in week().iter().enumerate() {
+                assert_eq!(timer.should_be_on_at(*other, None), n == m, "days = [{}] on {}", n, other);
             }
         }
+    }
 
-        interval.tick().await;
+    #[test]
+    fn overnight_windows_belong_to_the_day_they_start_on() {
+        // Sunday 22:00 - 06:00
+        let timer = Timer { start_time: 22 * 60, end_time: 6 * 60, days: vec![6], ..whole_day_timer(vec![]) };
+        let timezone: Tz = "Europe/Amsterdam".parse().unwrap();
+        let at = |day, hour| timezone.with_ymd_and_hms(2026, 10, day, hour, 0, 0).unwrap();
+
+        assert!(timer.should_be_on_at(at(25, 23), None));
+        assert!(timer.should_be_on_at(at(26, 5), None));
+        assert!(!timer.should_be_on_at(at(26, 23), None));
+        assert!(!timer.should_be_on_at(at(25, 5), None));
+    }
+
+    fn cron_timer(on_cron: &str, off_cron: &str) -> Timer {
+        Timer { on_cron: Some(on_cron.to_owned()), off_cron: Some(off_cron.to_owned()), ..whole_day_timer(vec![]) }
+    }
+
+    #[test]
+    fn cron_expressions_follow_the_local_time() {
+        // half an hour off from UTC, so hours counted in UTC would not line up
+        let timer = cron_timer("0 0 */2 * * *", "0 15 */2 * * *");
+        let timezone: Tz = "Asia/Kolkata".parse().unwrap();
+        let at = |hour, minute| timezone.with_ymd_and_hms(2026, 10, 19, hour, minute, 0).unwrap();
+
+        assert!(timer.validate().is_empty());
+        assert!(!timer.should_be_on_at(at(9, 59), None));
+        assert!(timer.should_be_on_at(at(10, 0), None));
+        assert!(timer.should_be_on_at(at(10, 14), None));
+        assert!(!timer.should_be_on_at(at(10, 15), None));
+        assert!(!timer.should_be_on_at(at(11, 5), None));
+        assert!(timer.should_be_on_at(at(0, 5), None));
+    }
+
+    #[test]
+    fn cron_days_of_the_month_and_of_the_week_must_both_match() {
+        // the first Monday of the month from 8 to 9
+        let timer = cron_timer("0 0 8 1-7 * Mon", "0 0 9 1-7 * Mon");
+        let timezone: Tz = "America/New_York".parse().unwrap();
+        let at = |month, day, hour, minute| timezone.with_ymd_and_hms(2026, month, day, hour, minute, 0).unwrap();
+
+        assert!(timer.validate().is_empty());
+        // Monday 2026-11-02, the day after clocks went back
+        assert!(!timer.should_be_on_at(at(11, 2, 7, 59), None));
+        assert!(timer.should_be_on_at(at(11, 2, 8, 0), None));
+        assert!(timer.should_be_on_at(at(11, 2, 8, 59), None));
+        assert!(!timer.should_be_on_at(at(11, 2, 9, 0), None));
+        // other days in the first week, and the second Monday
+        assert!(!timer.should_be_on_at(at(11, 3, 8, 30), None));
+        assert!(!timer.should_be_on_at(at(11, 9, 8, 30), None));
+        // Monday 2026-06-01 is the first day of its month
+        assert!(timer.should_be_on_at(at(6, 1, 8, 30), None));
+        assert!(!timer.should_be_on_at(at(6, 8, 8, 30), None));
+    }
+
+    const AMSTERDAM: LocationConfig = LocationConfig { latitude: 52.37, longitude: 4.90 };
+    const OSLO: LocationConfig = LocationConfig { latitude: 59.91, longitude: 10.75 };
+
+    fn sun(event: SunEvent, offset_minutes: i32) -> Option<SunTrigger> {
+        Some(SunTrigger { event, offset_minutes })
+    }
+
+    // `timezone` on the given day of 2026
+    fn local(timezone: &str, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Tz> {
+        timezone.parse::<Tz>().unwrap().with_ymd_and_hms(2026, month, day, hour, minute, 0).unwrap()
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn sunrise_ends_a_morning_window_and_skips_the_days_it_comes_first() {
+        // 06:00 until sunrise, every day
+        let timer = Timer { start_time: 6 * 60, end_sun: sun(SunEvent::Sunrise, 0), ..whole_day_timer((0..7).collect()) };
+        let at = |month, day, hour, minute| timer.should_be_on_at(local("Europe/Amsterdam", month, day, hour, minute), Some(&AMSTERDAM));
+
+        // sunrise around 08:48 in december
+        assert!(!at(12, 21, 5, 59));
+        assert!(at(12, 21, 6, 0));
+        assert!(at(12, 21, 8, 30));
+        assert!(!at(12, 21, 9, 0));
+        assert!(!at(12, 21, 23, 0));
+        // and 05:18 in june, the window is empty rather than running until the next sunrise
+        for hour in [0, 5, 6, 12, 23] {
+            assert!(!at(6, 21, hour, 30), "on at {}:30 in june", hour);
+        }
+    }
+
+    #[test]
+    fn sunset_offsets_past_a_fixed_end_skip_the_day() {
+        // 30 minutes after sunset until 23:00
+        let timer = Timer { start_sun: sun(SunEvent::Sunset, 30), end_time: 23 * 60, ..whole_day_timer((0..7).collect()) };
+        let at = |month, day, hour, minute| timer.should_be_on_at(local("Europe/Oslo", month, day, hour, minute), Some(&OSLO));
+
+        // sunset around 19:15 in september
+        assert!(!at(9, 21, 19, 30));
+        assert!(at(9, 21, 20, 0));
+        assert!(at(9, 21, 23, 0));
+        assert!(!at(9, 21, 23, 1));
+        assert!(!at(9, 22, 3, 0));
+        // and 22:44 in june, the start would come after 23:00
+        for (hour, minute) in [(23, 0), (23, 30), (3, 0), (12, 0)] {
+            assert!(!at(6, 21, hour, minute), "on at {}:{:02} in june", hour, minute);
+        }
+    }
+
+    #[test]
+    fn sunset_to_sunrise_runs_overnight_all_year() {
+        // from sunset on Sunday until sunrise on Monday
+        let timer = Timer { start_sun: sun(SunEvent::Sunset, 0), end_sun: sun(SunEvent::Sunrise, 0), ..whole_day_timer(vec![6]) };
+        let at = |month, day, hour, minute| timer.should_be_on_at(local("Europe/Amsterdam", month, day, hour, minute), Some(&AMSTERDAM));
+
+        // Sunday 2026-06-21 22:06 until Monday 05:18
+        assert!(!at(6, 21, 22, 0));
+        assert!(at(6, 21, 22, 15));
+        assert!(at(6, 22, 5, 0));
+        assert!(!at(6, 22, 5, 30));
+        assert!(!at(6, 22, 22, 15));
+        // Sunday 2026-12-20 16:29 until Monday 08:48
+        assert!(at(12, 20, 17, 0));
+        assert!(at(12, 21, 8, 30));
+        assert!(!at(12, 21, 9, 0));
+        assert!(!at(12, 21, 17, 0));
+    }
+
+    #[test]
+    fn evening_starts_run_until_sunrise_the_next_morning() {
+        // 22:00 until sunrise, every day
+        let timer = Timer { start_time: 22 * 60, end_sun: sun(SunEvent::Sunrise, 0), ..whole_day_timer((0..7).collect()) };
+        let at = |month, day, hour, minute| timer.should_be_on_at(local("Europe/Amsterdam", month, day, hour, minute), Some(&AMSTERDAM));
+
+        assert!(at(6, 21, 23, 0));
+        assert!(at(6, 22, 5, 0));
+        assert!(!at(6, 22, 5, 30));
+        assert!(!at(6, 22, 12, 0));
+        assert!(at(12, 21, 8, 30));
+    }
+
+    #[test]
+    fn sun_windows_need_a_location() {
+        let timer = Timer { start_sun: sun(SunEvent::Sunset, 0), end_time: 23 * 60 + 59, ..whole_day_timer((0..7).collect()) };
+
+        assert!(timer.should_be_on_at(local("Europe/Amsterdam", 12, 21, 23, 0), Some(&AMSTERDAM)));
+        assert!(!timer.should_be_on_at(local("Europe/Amsterdam", 12, 21, 23, 0), None));
+    }
+
+    #[test]
+    fn migrating_drops_days_out_of_range() {
+        let timers = migrate_timers(0, vec![whole_day_timer(vec![0, 6, 7, 9])]);
+        assert_eq!(timers[0].days, vec![0, 6]);
+    }
+
+    #[test]
+    fn files_without_a_version_are_stamped_without_shifting_days() {
+        let dir = std::env::temp_dir().join(format!("rsm-timers-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let timers_toml = dir.join("timers.toml");
+        std::fs::write(&timers_toml, r#"
+            [[timers]]
+            id = 1
+            switchId = 2
+            startTime = 480
+            endTime = 540
+            days = [0, 4]
+            isActive = true
+            oneOff = false
+        "#).unwrap();
+
+        let timers = parse_timers_from(&timers_toml);
+        assert_eq!(timers.len(), 1);
+        assert_eq!(timers[0].days, vec![0, 4]);
+
+        let stored: TimersArray = toml::from_str(&std::fs::read_to_string(&timers_toml).unwrap()).unwrap();
+        assert_eq!(stored.version, TIMERS_SCHEMA_VERSION);
+        assert_eq!(stored.timers[0].days, vec![0, 4]);
+        assert_eq!(stored.timers[0].start_time, 480);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn switches_without_status_feedback_are_switched_by_timers() {
+        let config = CommandConfig { on_command: vec!["true".to_owned()], off_command: vec!["true".to_owned()], status_command: None, timeout_seconds: 5 };
+        let switch: SafeSwitch = Arc::new(SwitchHandle::new(Box::new(CommandSwitch::new(device_data("", Device::Command(config.clone())), config))));
+        assert_eq!(switch.snapshot().status, None);
+
+        apply_switch_timers(&switch, &[whole_day_timer(vec![0, 1, 2, 3, 4, 5, 6])], None, &None, None).await;
+        assert_eq!(switch.snapshot().status, Some(DeviceStatus::On));
+    }
+
+    #[tokio::test]
+    async fn busy_switches_are_not_waited_on_when_nothing_is_due() {
+        let config = VirtualConfig { latency_ms: 60_000, ..Default::default() };
+        let switch: SafeSwitch = Arc::new(SwitchHandle::new(Box::new(VirtualSwitch::new(device_data("", Device::Virtual(config.clone())), config))));
+
+        let busy = switch.clone();
+        let poll = tokio::spawn(async move { busy.lock().await.update_status().await });
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+        // off all day long, as the switch already is
+        let timers = [Timer { start_time: 0, end_time: 0, days: vec![], ..whole_day_timer(vec![]) }];
+        let check = apply_switch_timers(&switch, &timers, None, &None, None);
+        assert!(tokio::time::timeout(tokio::time::Duration::from_secs(1), check).await.is_ok());
+        poll.abort();
+    }
+
+    #[tokio::test]
+    async fn failing_countdowns_are_retried_with_backoff() {
+        let config = VirtualConfig { failure_rate: 1.0, ..Default::default() };
+        let switch: SafeSwitch = Arc::new(SwitchHandle::new(Box::new(VirtualSwitch::new(device_data("", Device::Virtual(config.clone())), config))));
+        let countdown = Countdown { switch_id: 1, then_on: false, deadline: Utc::now() - chrono::Duration::seconds(1) };
+        let failures = || switch.snapshot().availability.consecutive_failures;
+
+        let outcome = apply_switch_timers(&switch, &[], Some(&countdown), &None, None).await;
+        assert!(!outcome.countdown_done);
+        assert_eq!(failures(), 1);
+
+        // still backing off
+        apply_switch_timers(&switch, &[], Some(&countdown), &None, None).await;
+        assert_eq!(failures(), 1);
+
+        switch.lock().await.get_device_data_mut().availability.last_failure = Some(Utc::now() - chrono::Duration::seconds(10));
+        apply_switch_timers(&switch, &[], Some(&countdown), &None, None).await;
+        assert_eq!(failures(), 2);
+    }
+}
@@ -0,0 +1,145 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Timelike, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::config::LocationConfig;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SunEvent {
+    Sunrise,
+    Sunset,
+}
+
+/*
+* A time of day relative to the sun, e.g. `{ event = "sunset", offsetMinutes = 30 }` for half an hour after sunset.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SunTrigger {
+    pub event: SunEvent,
+    #[serde(default)]
+    pub offset_minutes: i32,
+}
+
+impl SunTrigger {
+    /*
+    * Minutes after midnight of `date` in `timezone`, recomputed for every date. None when the sun does not
+    * rise/set that day, or the offset moves the time to another day.
+    */
+    pub fn minutes_on(&self, date: NaiveDate, timezone: &Tz, location: &LocationConfig) -> Option<u32> {
+        let (sunrise, sunset) = sun_times(date, location.latitude, location.longitude)?;
+        let time = match self.event {
+            SunEvent::Sunrise => sunrise,
+            SunEvent::Sunset => sunset,
+        } + Duration::minutes(self.offset_minutes as i64);
+
+        let local = time.with_timezone(timezone);
+        (local.date_naive() == date).then(|| local.hour() * 60 + local.minute())
+    }
+}
+
+/*
+* Sunrise and sunset following NOAA's "General Solar Position Calculations", accurate to a minute or two
+* which is plenty for switching lights. Nothing is looked up over the network.
+* Returns None for the days the sun does not rise or does not set (polar night/midnight sun).
+*/
+pub fn sun_times(date: NaiveDate, latitude: f64, longitude: f64) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let days_in_year = if date.leap_year() { 366.0 } else { 365.0 };
+    // fractional year in radians, taken at noon
+    let gamma = 2.0 * std::f64::consts::PI / days_in_year * (date.ordinal0() as f64 + 0.5);
+
+    // minutes
+    let equation_of_time = 229.18
+        * (0.000075 + 0.001868 * gamma.cos()
+            - 0.032077 * gamma.sin()
+            - 0.014615 * (2.0 * gamma).cos()
+            - 0.040849 * (2.0 * gamma).sin());
+
+    // radians
+    let declination = 0.006918 - 0.399912 * gamma.cos() + 0.070257 * gamma.sin()
+        - 0.006758 * (2.0 * gamma).cos()
+        + 0.000907 * (2.0 * gamma).sin()
+        - 0.002697 * (3.0 * gamma).cos()
+        + 0.00148 * (3.0 * gamma).sin();
+
+    // 90.833 degrees accounts for the atmospheric refraction and the size of the solar disk
+    let latitude = latitude.to_radians();
+    let cos_hour_angle = 90.833_f64.to_radians().cos() / (latitude.cos() * declination.cos())
+        - latitude.tan() * declination.tan();
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+
+    // minutes from midnight UTC, they can fall on the day before or after far from Greenwich
+    let sunrise = 720.0 - 4.0 * (longitude + hour_angle) - equation_of_time;
+    let sunset = 720.0 - 4.0 * (longitude - hour_angle) - equation_of_time;
+
+    let midnight = date.and_hms_opt(0, 0, 0)?.and_utc();
+    Some((
+        midnight + Duration::seconds((sunrise * 60.0) as i64),
+        midnight + Duration::seconds((sunset * 60.0) as i64),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AMSTERDAM: LocationConfig = LocationConfig { latitude: 52.37, longitude: 4.90 };
+    const OSLO: LocationConfig = LocationConfig { latitude: 59.91, longitude: 10.75 };
+    const TROMSO: LocationConfig = LocationConfig { latitude: 69.65, longitude: 18.96 };
+    const AUCKLAND: LocationConfig = LocationConfig { latitude: -36.85, longitude: 174.76 };
+
+    fn date(month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2026, month, day).unwrap()
+    }
+
+    fn trigger(event: SunEvent, offset_minutes: i32) -> SunTrigger {
+        SunTrigger { event, offset_minutes }
+    }
+
+    // Within a few minutes of the published times
+    fn assert_around(minutes: Option<u32>, hour: u32, minute: u32) {
+        let minutes = minutes.expect("no time on that day");
+        assert!(minutes.abs_diff(hour * 60 + minute) <= 3, "{:02}:{:02} instead of {:02}:{:02}", minutes / 60, minutes % 60, hour, minute);
+    }
+
+    #[test]
+    fn times_follow_the_seasons_in_local_time() {
+        let amsterdam: Tz = "Europe/Amsterdam".parse().unwrap();
+        let sunrise = trigger(SunEvent::Sunrise, 0);
+        let sunset = trigger(SunEvent::Sunset, 0);
+
+        assert_around(sunrise.minutes_on(date(6, 21), &amsterdam, &AMSTERDAM), 5, 18);
+        assert_around(sunset.minutes_on(date(6, 21), &amsterdam, &AMSTERDAM), 22, 6);
+        assert_around(sunrise.minutes_on(date(12, 21), &amsterdam, &AMSTERDAM), 8, 48);
+        assert_around(sunset.minutes_on(date(12, 21), &amsterdam, &AMSTERDAM), 16, 29);
+    }
+
+    #[test]
+    fn times_far_from_greenwich_land_on_the_local_day() {
+        // both happen on the day before in UTC
+        let auckland: Tz = "Pacific/Auckland".parse().unwrap();
+
+        assert_around(trigger(SunEvent::Sunrise, 0).minutes_on(date(6, 21), &auckland, &AUCKLAND), 7, 33);
+        assert_around(trigger(SunEvent::Sunset, 0).minutes_on(date(6, 21), &auckland, &AUCKLAND), 17, 12);
+    }
+
+    #[test]
+    fn offsets_are_added_but_not_into_another_day() {
+        let oslo: Tz = "Europe/Oslo".parse().unwrap();
+
+        assert_around(trigger(SunEvent::Sunset, 30).minutes_on(date(6, 21), &oslo, &OSLO), 23, 14);
+        assert_around(trigger(SunEvent::Sunrise, -60).minutes_on(date(6, 21), &oslo, &OSLO), 2, 54);
+        assert_eq!(trigger(SunEvent::Sunset, 90).minutes_on(date(6, 21), &oslo, &OSLO), None);
+    }
+
+    #[test]
+    fn polar_days_and_nights_have_no_times() {
+        assert!(sun_times(date(6, 21), TROMSO.latitude, TROMSO.longitude).is_none());
+        assert!(sun_times(date(12, 21), TROMSO.latitude, TROMSO.longitude).is_none());
+        assert!(sun_times(date(3, 21), TROMSO.latitude, TROMSO.longitude).is_some());
+    }
+}